@@ -0,0 +1,446 @@
+//! Idiomatic borrowed forms of owned types: a parameter only read through
+//! its deref target is retyped to `&str` rather than `&String`, `&[T]`
+//! rather than `&Vec<T>`, `&T` rather than `&Box<T>`, `&Rc<T>` or
+//! `&Arc<T>`, `&str` rather than `&Rc<String>`, and `Option<&T>` rather
+//! than `&Option<T>`.
+
+use crate::conversion::{owned, Conversions, Owned};
+use rustc_hir::def_id::DefId;
+use rustc_middle::mir::{
+    visit::{MutatingUseContext, NonMutatingUseContext, PlaceContext, Visitor},
+    BasicBlock, Body, BorrowKind, Local, Location, Operand, Place, ProjectionElem, Rvalue,
+    StatementKind, TerminatorKind, RETURN_PLACE,
+};
+use rustc_middle::ty::{fast_reject::SimplifiedType, TyCtxt, TyKind};
+
+/// The type a local is retyped to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Form {
+    /// `&'a T`
+    Ref,
+    /// `&'a str` for a `String`
+    Str,
+    /// `&'a [T]` for a `Vec<T>`
+    Slice,
+    /// `&'a T` for a `Box<T>`
+    Boxed,
+    /// `&'a T` for an `Rc<T>` or `Arc<T>`, or the form of `T` if any:
+    /// `&'a str` for an `Rc<String>`
+    Shared(Option<&'static Form>),
+    /// `Option<&'a T>` for an `Option<T>`
+    Option,
+}
+impl Form {
+    /// The idiomatic form of the type of `local`, if every use of `local`
+    /// in `body` works on it as well; [`Form::Ref`] otherwise.
+    pub(crate) fn of<'tcx>(
+        ctx: &TyCtxt<'tcx>,
+        body: &Body<'tcx>,
+        local: Local,
+        conversions: &Conversions,
+    ) -> Form {
+        let ty = body.local_decls[local].ty;
+        let form = match owned(ctx, ty) {
+            Owned::String => Form::Str,
+            Owned::Vec => Form::Slice,
+            Owned::Box => Form::Boxed,
+            Owned::Shared => {
+                let content = match ty.kind() {
+                    TyKind::Adt(_, args) => owned(ctx, args.type_at(0)),
+                    _ => Owned::Other,
+                };
+                let composed = match content {
+                    Owned::String => Form::Shared(Some(&Form::Str)),
+                    Owned::Vec => Form::Shared(Some(&Form::Slice)),
+                    _ => Form::Shared(None),
+                };
+                if composed != Form::Shared(None)
+                    && composed.compatible(ctx, body, local, conversions)
+                {
+                    return composed;
+                }
+                Form::Shared(None)
+            }
+            Owned::Option => Form::Option,
+            Owned::Other => return Form::Ref,
+        };
+        if form.compatible(ctx, body, local, conversions) {
+            form
+        } else {
+            log::debug!("{local:?} is not only used through {form:?}");
+            Form::Ref
+        }
+    }
+
+    /// The form all of `forms` can take, for locals retyped together.
+    pub(crate) fn common(forms: &[Form]) -> Form {
+        match forms {
+            [] => Form::Ref,
+            [first, rest @ ..] if rest.iter().all(|form| form == first) => *first,
+            _ if forms.iter().all(|form| matches!(form, Form::Shared(_))) => Form::Shared(None),
+            _ => Form::Ref,
+        }
+    }
+
+    /// The annotation `ty` rewritten to this form with `lifetime`, elided if
+    /// empty, or `None` when `ty` is not spelled as expected (e.g. through an
+    /// alias).
+    pub(crate) fn retype(&self, lifetime: &str, ty: &str) -> Option<String> {
+        let inner = |name: &str| {
+            let (path, args) = ty.split_once('<')?;
+            (path.rsplit("::").next() == Some(name)).then_some(args.strip_suffix('>')?)
+        };
+        match self {
+            Form::Ref => Some(reference(lifetime, ty)),
+            Form::Str if ty.ends_with("String") => Some(reference(lifetime, "str")),
+            Form::Slice => inner("Vec").map(|elem| reference(lifetime, &format!("[{elem}]"))),
+            Form::Boxed => inner("Box").map(|elem| reference(lifetime, elem)),
+            Form::Shared(content) => {
+                let elem = inner("Rc").or_else(|| inner("Arc"))?;
+                match content {
+                    Some(form) => form.retype(lifetime, elem),
+                    None => Some(reference(lifetime, elem)),
+                }
+            }
+            Form::Option => {
+                inner("Option").map(|elem| format!("Option<{}>", reference(lifetime, elem)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `local` is only moved to other locals, returned, dropped, or
+    /// borrowed for calls that also accept this form.
+    fn compatible<'tcx>(
+        &self,
+        ctx: &TyCtxt<'tcx>,
+        body: &Body<'tcx>,
+        local: Local,
+        conversions: &Conversions,
+    ) -> bool {
+        let mut borrows = Vec::new();
+        for (context, location) in uses(body, local) {
+            let Some(StatementKind::Assign(assign)) = statement(body, location) else {
+                if !context.is_drop()
+                    && !is_conversion(ctx, body, location, conversions)
+                    && !is_return(body, local, location)
+                {
+                    return false;
+                }
+                continue;
+            };
+            match &assign.1 {
+                Rvalue::Ref(_, BorrowKind::Shared, place) if place.projection.is_empty() => {
+                    borrows.push((assign.0.local, *self));
+                }
+                // `&*b` borrows the content of a box, as the retyped `b` does
+                Rvalue::Ref(_, BorrowKind::Shared, place)
+                    if *self == Form::Boxed && place.projection[..] == [ProjectionElem::Deref] => {}
+                // to another local, retyped the same way
+                Rvalue::Use(Operand::Move(place) | Operand::Copy(place))
+                    if place.projection.is_empty() && assign.0.projection.is_empty() => {}
+                _ => return false,
+            }
+        }
+        // the borrows, and the form their uses have to accept
+        while let Some((borrow, form)) = borrows.pop() {
+            for (context, location) in uses(body, borrow) {
+                if matches!(context, PlaceContext::MutatingUse(_)) {
+                    // the borrow itself
+                    continue;
+                }
+                match statement(body, location) {
+                    Some(StatementKind::Assign(assign)) => match &assign.1 {
+                        Rvalue::Ref(_, BorrowKind::Shared, place)
+                            if place.projection[..] == [ProjectionElem::Deref] =>
+                        {
+                            borrows.push((assign.0.local, form));
+                        }
+                        _ => return false,
+                    },
+                    Some(_) => return false,
+                    None => {
+                        let terminator = body.basic_blocks[location.block].terminator();
+                        let TerminatorKind::Call {
+                            func, destination, ..
+                        } = &terminator.kind
+                        else {
+                            return false;
+                        };
+                        let Some((def_id, _)) = func.const_fn_def() else {
+                            return false;
+                        };
+                        if !form.accepts(ctx, def_id, conversions) {
+                            log::debug!("{:?} does not accept {form:?}", ctx.def_path_str(def_id));
+                            return false;
+                        }
+                        // `&**rc`: the content of the handle is used in its own form
+                        if let (Form::Shared(Some(content)), Some(deref)) =
+                            (form, destination.as_local())
+                        {
+                            if ctx.trait_of_item(def_id) == ctx.lang_items().deref_trait() {
+                                borrows.push((deref, *content));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Whether a borrow of the owned type can be replaced by this form in a
+    /// call to `def_id`. The `conversions` are eliminated along with the
+    /// borrow, or kept and checked on their own.
+    fn accepts(&self, ctx: &TyCtxt<'_>, def_id: DefId, conversions: &Conversions) -> bool {
+        let lang_items = ctx.lang_items();
+        let trait_id = ctx.trait_of_item(def_id);
+        if (trait_id.is_some() && trait_id == lang_items.deref_trait())
+            || conversions.find(ctx, def_id).is_some()
+        {
+            return true;
+        }
+        if ctx
+            .def_path_str(def_id)
+            .starts_with("core::fmt::rt::Argument::")
+        {
+            // formatted, `str` and slices implement the same traits
+            return *self != Form::Option;
+        }
+        if trait_id.is_some() && trait_id == lang_items.eq_trait() {
+            return matches!(self, Form::Str | Form::Slice);
+        }
+        if trait_id.is_some() && trait_id == lang_items.index_trait() {
+            return *self == Form::Slice;
+        }
+        if trait_id.is_some() || ctx.impl_of_method(def_id).is_none() {
+            return false;
+        }
+        let name = ctx.item_name(def_id);
+        let has_method = |simplified| {
+            ctx.incoherent_impls(simplified).iter().any(|impl_id| {
+                ctx.associated_item_def_ids(*impl_id)
+                    .iter()
+                    .any(|item| ctx.item_name(*item) == name)
+            })
+        };
+        match self {
+            Form::Str => has_method(SimplifiedType::Str),
+            Form::Slice => has_method(SimplifiedType::Slice),
+            Form::Option => matches!(name.as_str(), "is_some" | "is_none" | "as_ref"),
+            Form::Ref | Form::Boxed | Form::Shared(_) => false,
+        }
+    }
+}
+
+/// `&'a ty` with `lifetime` `'a`, or `&ty` if it is empty.
+pub(crate) fn reference(lifetime: &str, ty: &str) -> String {
+    if lifetime.is_empty() {
+        format!("&{ty}")
+    } else {
+        format!("&{lifetime} {ty}")
+    }
+}
+
+/// Whether the terminator at `location` calls one of the `conversions`,
+/// which assigns a clone to be eliminated.
+fn is_conversion(
+    ctx: &TyCtxt<'_>,
+    body: &Body<'_>,
+    location: Location,
+    conversions: &Conversions,
+) -> bool {
+    let terminator = body.basic_blocks[location.block].terminator();
+    let TerminatorKind::Call { func, .. } = &terminator.kind else {
+        return false;
+    };
+    func.const_fn_def()
+        .is_some_and(|(def_id, _)| conversions.find(ctx, def_id).is_some())
+}
+
+/// Whether `local` is the return place, returned by the terminator at
+/// `location`: the return type is retyped with the locals moved to it.
+fn is_return(body: &Body<'_>, local: Local, location: Location) -> bool {
+    local == RETURN_PLACE
+        && matches!(
+            body.basic_blocks[location.block].terminator().kind,
+            TerminatorKind::Return
+        )
+}
+
+/// Whether the value of `local`, or of a local it is moved to, is moved
+/// out of the body or into anything but another local: returned, passed
+/// by value, stored in an aggregate or captured by a closure.
+pub(crate) fn escapes(body: &Body<'_>, local: Local) -> bool {
+    let mut locals = vec![local];
+    while let Some(local) = locals.pop() {
+        for (context, location) in uses(body, local) {
+            if matches!(
+                context,
+                PlaceContext::MutatingUse(MutatingUseContext::Store | MutatingUseContext::Call)
+            ) {
+                // assigned
+                continue;
+            }
+            let Some(StatementKind::Assign(assign)) = statement(body, location) else {
+                if context.is_drop() {
+                    continue;
+                }
+                return true;
+            };
+            match &assign.1 {
+                Rvalue::Ref(_, BorrowKind::Shared, _) => {}
+                Rvalue::Use(Operand::Move(place) | Operand::Copy(place))
+                    if place.projection.is_empty() && assign.0.projection.is_empty() =>
+                {
+                    if assign.0.local == RETURN_PLACE {
+                        return true;
+                    }
+                    locals.push(assign.0.local);
+                }
+                _ => return true,
+            }
+        }
+    }
+    false
+}
+
+/// The place borrowed into `operand` by the statements of `block`, as the
+/// argument of a clone is: `_3 = &_1; _2 = <T as Clone>::clone(move _3)`.
+pub(crate) fn borrowed_place<'tcx>(
+    body: &Body<'tcx>,
+    block: BasicBlock,
+    operand: &Operand<'tcx>,
+) -> Option<Place<'tcx>> {
+    let borrow = match operand {
+        Operand::Move(place) | Operand::Copy(place) => place.as_local()?,
+        Operand::Constant(_) => return None,
+    };
+    let assign = body.basic_blocks[block]
+        .statements
+        .iter()
+        .rev()
+        .find_map(|stmt| match &stmt.kind {
+            StatementKind::Assign(assign) if assign.0.as_local() == Some(borrow) => Some(assign),
+            _ => None,
+        })?;
+    match &assign.1 {
+        Rvalue::Ref(_, BorrowKind::Shared, place) => Some(*place),
+        _ => None,
+    }
+}
+
+/// The statement at `location`, `None` for a terminator.
+pub(crate) fn statement<'a, 'tcx>(
+    body: &'a Body<'tcx>,
+    location: Location,
+) -> Option<&'a StatementKind<'tcx>> {
+    body.basic_blocks[location.block]
+        .statements
+        .get(location.statement_index)
+        .map(|stmt| &stmt.kind)
+}
+
+/// Every use of `local`, storage markers, type ascriptions and `FakeRead`s aside.
+pub(crate) fn uses(body: &Body<'_>, local: Local) -> Vec<(PlaceContext, Location)> {
+    struct Uses {
+        local: Local,
+        uses: Vec<(PlaceContext, Location)>,
+    }
+    impl Visitor<'_> for Uses {
+        fn visit_local(&mut self, local: Local, context: PlaceContext, location: Location) {
+            if local != self.local
+                || matches!(
+                    context,
+                    PlaceContext::NonUse(_)
+                        | PlaceContext::NonMutatingUse(NonMutatingUseContext::Inspect)
+                )
+            {
+                return;
+            }
+            self.uses.push((context, location));
+        }
+    }
+    let mut uses = Uses {
+        local,
+        uses: Vec::new(),
+    };
+    uses.visit_body(body);
+    uses.uses
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::Options;
+
+    /// `source` with the clones of the function `name` eliminated.
+    fn rewrite(source: &str, name: &str) -> String {
+        let rewrite = fixture::rewrite("borrowed_fixture.rs", source, name, &Options::default());
+        fixture::rewritten(&rewrite)
+    }
+
+    #[test]
+    fn str_parameter() {
+        assert_eq!(
+            rewrite(
+                "fn f(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}",
+                "f"
+            ),
+            "fn f(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}"
+        );
+    }
+
+    #[test]
+    fn returned_slice() {
+        // the return type takes the form of the parameter moved to it
+        assert_eq!(
+            rewrite(
+                "fn f(a: Vec<u8>) -> Vec<u8> {\n    let b = a.clone();\n    if b.is_empty() { a } else { b }\n}\n\
+                 fn g() -> usize {\n    f(Vec::new()).len()\n}",
+                "f"
+            ),
+            "fn f(a: &[u8]) -> &[u8] {\n    let b = a;\n    if b.is_empty() { a } else { b }\n}\n\
+             fn g() -> usize {\n    f(&Vec::new()).len()\n}"
+        );
+    }
+
+    #[test]
+    fn option() {
+        assert_eq!(
+            rewrite(
+                "fn f(a: Option<String>) -> bool {\n    let b = a.clone();\n    b.is_some() && a.is_none()\n}\n\
+                 fn g(s: String) -> bool {\n    f(Some(s)) || f(None)\n}",
+                "f"
+            ),
+            "fn f(a: Option<&String>) -> bool {\n    let b = a;\n    b.is_some() && a.is_none()\n}\n\
+             fn g(s: String) -> bool {\n    f(Some(&s)) || f(None)\n}"
+        );
+    }
+
+    #[test]
+    fn shared_content() {
+        // `&str` and `&[T]` rather than `&String` and `&Vec<T>`, unless the
+        // content is used as it is
+        assert_eq!(
+            rewrite(
+                "use std::rc::Rc;\n\
+                 fn f(v: Rc<Vec<u8>>) -> usize {\n    let w = v.clone();\n    w.len() + v.len()\n}",
+                "f"
+            ),
+            "use std::rc::Rc;\n\
+             fn f(v: &[u8]) -> usize {\n    let w = v;\n    w.len() + v.len()\n}"
+        );
+        assert_eq!(
+            rewrite(
+                "use std::rc::Rc;\n\
+                 fn takes(s: &String) -> usize {\n    s.len()\n}\n\
+                 fn f(s: Rc<String>) -> usize {\n    let t = s.clone();\n    takes(&t) + s.len()\n}",
+                "f"
+            ),
+            "use std::rc::Rc;\n\
+             fn takes(s: &String) -> usize {\n    s.len()\n}\n\
+             fn f(s: &String) -> usize {\n    let t = s;\n    takes(&t) + s.len()\n}"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Adjustment of the arguments at the call sites of a function whose
+//! parameters were retyped to references.
+
+use crate::borrowed::Form;
+use crate::check;
+use crate::conversion::Conversions;
+use crate::enter;
+use crate::rewrite::Substitutes;
+use rustc_hir::{
+    def::{DefKind, Res},
+    def_id::LocalDefId,
+    intravisit::{self, Visitor},
+    Expr, ExprKind, HirId, LangItem, QPath,
+};
+use rustc_middle::hir::nested_filter;
+use rustc_middle::ty::{TyCtxt, TypeckResults};
+
+/// Edits passing a borrow instead of a value for the `params` of `def_id`
+/// at every call in the crate: `f(x)` and `f(x.clone())` (or any other
+/// of the `conversions`) become `f(&x)`, or `f(x.as_ref())` and
+/// `f(Some(&x))` for a parameter retyped to `Option<&T>`. A receiver is
+/// left as is since method calls borrow it automatically.
+pub fn borrow_args(
+    ctx: &TyCtxt<'_>,
+    def_id: LocalDefId,
+    params: &[(usize, Form)],
+    conversions: &Conversions,
+) -> Substitutes {
+    // the retyped parameters themselves are already references
+    let retyped = ctx
+        .hir()
+        .body_owned_by(def_id)
+        .params
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| params.iter().any(|(param, _)| param == i))
+        .map(|(_, param)| param.pat.hir_id)
+        .collect();
+    let mut calls = Calls {
+        ctx: *ctx,
+        typeck: None,
+        def_id,
+        params,
+        conversions,
+        retyped,
+        substitutes: Substitutes::new(),
+    };
+    let callers = check::callers(ctx, &[def_id]);
+    // recursive calls included
+    for caller in std::iter::once(def_id).chain(callers) {
+        if !enter::in_source_file(ctx, ctx.def_span(caller)) {
+            log::debug!("caller {caller:?} is not in a source file; skipped");
+            continue;
+        }
+        calls.typeck = Some(ctx.typeck(caller));
+        calls.visit_body(ctx.hir().body_owned_by(caller));
+    }
+    calls.substitutes
+}
+
+struct Calls<'a, 'tcx> {
+    ctx: TyCtxt<'tcx>,
+    typeck: Option<&'tcx TypeckResults<'tcx>>,
+    def_id: LocalDefId,
+    params: &'a [(usize, Form)],
+    conversions: &'a Conversions,
+    retyped: Vec<HirId>,
+    substitutes: Substitutes,
+}
+impl<'tcx> Calls<'_, 'tcx> {
+    /// Passes `arg` by reference, or as an `Option` of a reference.
+    fn borrow(&mut self, arg: &'tcx Expr<'tcx>, form: Form) {
+        if arg.span.from_expansion() {
+            return;
+        }
+        if let ExprKind::Path(QPath::Resolved(None, path)) = arg.kind {
+            if matches!(path.res, Res::Local(id) if self.retyped.contains(&id)) {
+                return;
+            }
+        }
+        let typeck = self.typeck.unwrap();
+        if form == Form::Option && is_ctor(self.ctx, typeck, arg, LangItem::OptionNone) {
+            return;
+        }
+        if let ExprKind::Call(callee, [value]) = arg.kind {
+            if form == Form::Option && is_ctor(self.ctx, typeck, callee, LangItem::OptionSome) {
+                // `Some(&x)` rather than `Some(x.clone()).as_ref()`
+                self.borrow(value, Form::Ref);
+                return;
+            }
+        }
+        let (lo, hi) = (arg.span.lo().0, arg.span.hi().0);
+        if let ExprKind::MethodCall(_, receiver, [], _) = arg.kind {
+            let conversion = typeck.type_dependent_def_id(arg.hir_id).and_then(|id| {
+                self.conversions.borrow(
+                    &self.ctx,
+                    id,
+                    typeck.expr_ty_adjusted(receiver),
+                    typeck.expr_ty(arg),
+                )
+            });
+            if conversion.is_some() {
+                log::info!("drop the conversion of argument {:?}", arg.span);
+                if form == Form::Option {
+                    self.substitutes
+                        .rewrite(receiver.span.hi().0, hi, ".as_ref()".to_owned());
+                } else {
+                    self.substitutes.rewrite(lo, lo, "&".to_owned());
+                    self.substitutes
+                        .rewrite(receiver.span.hi().0, hi, "".to_owned());
+                }
+                return;
+            }
+        }
+        log::info!("borrow argument {:?}", arg.span);
+        let (open, close) = match form {
+            Form::Option => ("", ".as_ref()"),
+            _ => ("&", ""),
+        };
+        match arg.kind {
+            ExprKind::Path(..)
+            | ExprKind::Call(..)
+            | ExprKind::MethodCall(..)
+            | ExprKind::Field(..)
+            | ExprKind::Index(..)
+            | ExprKind::Lit(..)
+            | ExprKind::Struct(..)
+            | ExprKind::Tup(..)
+            | ExprKind::Array(..)
+            | ExprKind::Block(..) => {
+                self.substitutes.rewrite(lo, lo, open.to_owned());
+                self.substitutes.rewrite(hi, hi, close.to_owned());
+            }
+            _ => {
+                self.substitutes.rewrite(lo, lo, format!("{open}("));
+                self.substitutes.rewrite(hi, hi, format!("){close}"));
+            }
+        }
+    }
+}
+impl<'tcx> Visitor<'tcx> for Calls<'_, 'tcx> {
+    type NestedFilter = nested_filter::OnlyBodies;
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.ctx.hir()
+    }
+    fn visit_expr(&mut self, expr: &'tcx Expr<'tcx>) {
+        let typeck = self.typeck.unwrap();
+        // the arguments of a call to the function, `self` first
+        let args: Vec<Option<&'tcx Expr<'tcx>>> = match expr.kind {
+            ExprKind::Call(callee, args) => match &callee.kind {
+                ExprKind::Path(qpath) => {
+                    match typeck.qpath_res(qpath, callee.hir_id).opt_def_id() {
+                        Some(id) if check::refers_to(self.ctx, id, self.def_id) => {
+                            args.iter().map(Some).collect()
+                        }
+                        _ => Vec::new(),
+                    }
+                }
+                _ => Vec::new(),
+            },
+            ExprKind::MethodCall(_, _, args, _) => {
+                match typeck.type_dependent_def_id(expr.hir_id) {
+                    Some(id) if check::refers_to(self.ctx, id, self.def_id) => {
+                        std::iter::once(None).chain(args.iter().map(Some)).collect()
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        };
+        for (param, form) in self.params {
+            if let Some(Some(arg)) = args.get(*param) {
+                self.borrow(arg, *form);
+            }
+        }
+        intravisit::walk_expr(self, expr);
+    }
+}
+
+/// Whether `expr` is the constructor `ctor` of `Option`: `None`, which
+/// needs no adjustment, or the callee `Some`.
+fn is_ctor<'tcx>(
+    ctx: TyCtxt<'tcx>,
+    typeck: &TypeckResults<'tcx>,
+    expr: &Expr<'_>,
+    ctor: LangItem,
+) -> bool {
+    let ExprKind::Path(qpath) = &expr.kind else {
+        return false;
+    };
+    match typeck.qpath_res(qpath, expr.hir_id) {
+        Res::Def(DefKind::Ctor(..), id) => ctx.is_lang_item(ctx.parent(id), ctor),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Rejection;
+    use crate::Options;
+
+    const PATH: &str = "call_site_fixture.rs";
+
+    #[test]
+    fn caller_clone_dropped() {
+        let source =
+            "fn len(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}\n\
+                      fn g(o: String) -> usize {\n    len(o.clone()) + o.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "len", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn len(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}\n\
+             fn g(o: String) -> usize {\n    len(&o) + o.len()\n}"
+        );
+    }
+
+    #[test]
+    fn caller_clone_removed_once() {
+        // the clones of the callers are removed by the rewrite of the callees
+        let source = "use std::rc::Rc;\n\
+                      fn len(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}\n\
+                      fn rc(c: Rc<String>) -> usize {\n    let d = c.clone();\n    d.len() + c.len()\n}\n\
+                      fn g(o: String, cfg: Rc<String>) -> usize {\n    \
+                      len(o.clone()) + rc(cfg.clone()) + o.len() + cfg.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert_eq!(rewrite.eliminations.len(), 2);
+        assert!(rewrite.rejected.is_empty(), "{:?}", rewrite.rejected);
+        assert!(fixture::rewritten(&rewrite).contains("len(&o) + rc(&cfg)"));
+    }
+
+    #[test]
+    fn callee_not_retyped() {
+        // `private(s)` would move `s`
+        let source = "fn private(s: String) -> usize {\n    s.len()\n}\n\
+                      fn g(s: String) -> usize {\n    private(s.clone()) + s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(reasons, [&Rejection::CloneConsumed]);
+    }
+}
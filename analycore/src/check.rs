@@ -0,0 +1,240 @@
+//! Verification of a rewrite: type and borrow check of the rewritten bodies
+//! and their callers, with the errors captured instead of printed.
+
+use crate::{enter, future};
+use rustc_data_structures::sync::Lrc;
+use rustc_errors::emitter::Emitter;
+use rustc_errors::translation::{to_fluent_args, Translate};
+use rustc_errors::{DiagCtxt, DiagInner, FluentBundle, LazyFallbackBundle};
+use rustc_hir::{
+    def::DefKind,
+    def_id::{DefId, LocalDefId},
+    intravisit::{self, Visitor},
+    Expr, ExprKind,
+};
+use rustc_middle::hir::nested_filter;
+use rustc_middle::ty::{TyCtxt, TypeckResults};
+use rustc_session::parse::ParseSess;
+use rustc_span::source_map::SourceMap;
+use std::sync::{Arc, Mutex};
+
+/// Error diagnostics rendered as `file:line:col: message`.
+pub type Diagnostics = Arc<Mutex<Vec<String>>>;
+
+/// Emitter collecting the errors into [`Diagnostics`] and dropping the rest.
+struct Capture {
+    diagnostics: Diagnostics,
+    source_map: Lrc<SourceMap>,
+    fallback_bundle: LazyFallbackBundle,
+}
+impl Translate for Capture {
+    fn fluent_bundle(&self) -> Option<&FluentBundle> {
+        None
+    }
+    fn fallback_fluent_bundle(&self) -> &FluentBundle {
+        &self.fallback_bundle
+    }
+}
+impl Emitter for Capture {
+    fn source_map(&self) -> Option<&SourceMap> {
+        Some(&self.source_map)
+    }
+    fn emit_diagnostic(&mut self, diag: DiagInner) {
+        if !diag.is_error() {
+            return;
+        }
+        let args = to_fluent_args(diag.args.iter());
+        let message = self.translate_messages(&diag.messages, &args);
+        // the summary emitted at the end of a failed compilation
+        if message.starts_with("aborting due to") {
+            return;
+        }
+        let rendered = match diag.span.primary_span() {
+            Some(span) => format!(
+                "{}: {message}",
+                self.source_map.span_to_diagnostic_string(span)
+            ),
+            None => message.into_owned(),
+        };
+        log::debug!("captured error: {rendered}");
+        self.diagnostics.lock().unwrap().push(rendered);
+    }
+}
+
+/// Replaces the emitter of the session with one capturing the errors.
+pub fn capture(psess: &mut ParseSess, diagnostics: Diagnostics) {
+    let emitter = Capture {
+        diagnostics,
+        source_map: psess.clone_source_map(),
+        fallback_bundle: rustc_errors::fallback_fluent_bundle(
+            rustc_driver::DEFAULT_LOCALE_RESOURCES.to_vec(),
+            false,
+        ),
+    };
+    psess.set_dcx(DiagCtxt::new(Box::new(emitter)));
+}
+
+/// Type and borrow checks the `rewritten` bodies, the items they belong to
+/// (so that an impl method still matches its trait), the impl methods of
+/// the rewritten trait methods and every body calling them, and checks that
+/// the futures of [`future::send_futures`] listed in `send` are still
+/// `Send`. The errors are emitted to the session.
+pub fn check(ctx: &TyCtxt<'_>, rewritten: &[LocalDefId], send: &[String]) {
+    let mut items = rewritten.to_vec();
+    items.extend(
+        implementors(ctx, rewritten)
+            .into_iter()
+            .filter(|def_id| !rewritten.contains(def_id)),
+    );
+    let mut bodies = items.clone();
+    bodies.extend(callers(ctx, rewritten));
+    for def_id in &items {
+        let _ = ctx.check_well_formed(*def_id);
+        if matches!(ctx.def_kind(*def_id), DefKind::AssocFn) {
+            let _ = ctx.check_well_formed(ctx.local_parent(*def_id));
+        }
+    }
+    for def_id in bodies {
+        log::debug!("type & borrow check {}", ctx.def_path_str(def_id));
+        let _ = ctx.typeck(def_id);
+        let _ = ctx.mir_borrowck(def_id);
+    }
+    let futures = rewritten
+        .iter()
+        .flat_map(|def_id| std::iter::once(*def_id).chain(enter::nested_bodies(ctx, *def_id)));
+    for def_id in futures {
+        let path = ctx.def_path_str(def_id);
+        // a body with type errors has them reported already
+        let tainted = ctx.typeck(def_id).tainted_by_errors.is_some();
+        if send.contains(&path) && !tainted && !future::is_send(ctx, def_id) {
+            ctx.dcx().span_err(
+                ctx.def_span(def_id),
+                format!("the future of `{path}` is no longer `Send`"),
+            );
+        }
+    }
+}
+
+/// Type and borrow checks the whole crate, as a rewrite changing the type
+/// of a struct requires. The errors are emitted to the session.
+pub fn check_crate(ctx: &TyCtxt<'_>) {
+    log::debug!("type & borrow check the crate");
+    let _ = ctx.analysis(());
+}
+
+/// The impl methods of the crate implementing one of the trait methods
+/// among `fns`.
+fn implementors(ctx: &TyCtxt<'_>, fns: &[LocalDefId]) -> Vec<LocalDefId> {
+    let mut implementors = Vec::new();
+    for def_id in fns {
+        let Some(trait_id) = ctx.trait_of_item(def_id.to_def_id()) else {
+            continue;
+        };
+        let Some(impls) = ctx.all_local_trait_impls(()).get(&trait_id) else {
+            continue;
+        };
+        for imp in impls {
+            let implementor = ctx
+                .impl_item_implementor_ids(imp.to_def_id())
+                .get(&def_id.to_def_id())
+                .and_then(|item| item.as_local());
+            implementors.extend(implementor);
+        }
+    }
+    implementors
+}
+
+/// Bodies outside of `fns` that call or otherwise mention one of them.
+pub fn callers(ctx: &TyCtxt<'_>, fns: &[LocalDefId]) -> Vec<LocalDefId> {
+    let mut callers = Vec::new();
+    for def_id in ctx.hir().body_owners() {
+        if fns.contains(&def_id) || ctx.is_typeck_child(def_id.to_def_id()) {
+            continue;
+        }
+        let mut mentions = Mentions {
+            ctx: *ctx,
+            typeck: ctx.typeck(def_id),
+            fns,
+            found: false,
+        };
+        mentions.visit_body(ctx.hir().body_owned_by(def_id));
+        if mentions.found {
+            callers.push(def_id);
+        }
+    }
+    callers
+}
+
+/// Whether `def_id` is the function `f`, or the trait method `f` implements.
+pub(crate) fn refers_to(ctx: TyCtxt<'_>, def_id: DefId, f: LocalDefId) -> bool {
+    f.to_def_id() == def_id
+        || (matches!(ctx.def_kind(f), DefKind::AssocFn)
+            && ctx.associated_item(f).trait_item_def_id == Some(def_id))
+}
+
+struct Mentions<'a, 'tcx> {
+    ctx: TyCtxt<'tcx>,
+    typeck: &'tcx TypeckResults<'tcx>,
+    fns: &'a [LocalDefId],
+    found: bool,
+}
+impl<'tcx> Visitor<'tcx> for Mentions<'_, 'tcx> {
+    type NestedFilter = nested_filter::OnlyBodies;
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.ctx.hir()
+    }
+    fn visit_expr(&mut self, expr: &'tcx Expr<'tcx>) {
+        let def_id = match &expr.kind {
+            ExprKind::Path(qpath) => self.typeck.qpath_res(qpath, expr.hir_id).opt_def_id(),
+            ExprKind::MethodCall(..) => self.typeck.type_dependent_def_id(expr.hir_id),
+            _ => None,
+        };
+        if def_id.is_some_and(|def_id| self.fns.iter().any(|f| refers_to(self.ctx, def_id, *f))) {
+            self.found = true;
+        }
+        intravisit::walk_expr(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enter::get_fn;
+    use crate::{CompileResult, Error};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// The errors of checking `source` as a rewrite of the function `name`.
+    fn check_fn(source: &str, name: &str) -> Vec<String> {
+        let path = PathBuf::from("check_fixture.rs");
+        let sources = HashMap::from([(path.clone(), format!("{source}\nfn main() {{}}\n"))]);
+        let res = enter::enter(path, sources, |ctx| {
+            check(ctx, &[get_fn(ctx, name)?], &[]);
+            Ok(())
+        });
+        match res {
+            Ok(CompileResult::Ok(Ok(()))) => Vec::new(),
+            Err(Error::Compile(diagnostics)) => diagnostics,
+            res => panic!("{res:?}"),
+        }
+    }
+
+    #[test]
+    fn impl_of_rewritten_trait_method() {
+        // `s` of the provided method was a `String`, as it still is in the impl
+        let source = "trait Show {\n    fn twice(&self, s: &str) -> String {\n        s.repeat(2)\n    }\n}\n\
+                      struct A;\n\
+                      impl Show for A {\n    fn twice(&self, s: String) -> String {\n        s\n    }\n}";
+        let diagnostics = check_fn(source, "Show::twice");
+        assert!(
+            diagnostics
+                .iter()
+                .any(|d| d.contains("incompatible type for trait")),
+            "{diagnostics:?}"
+        );
+        let source = source
+            .replace("s: String", "s: &str")
+            .replace("        s\n", "        s.to_owned()\n");
+        assert_eq!(check_fn(&source, "Show::twice"), Vec::<String>::new());
+    }
+}
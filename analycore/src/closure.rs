@@ -0,0 +1,131 @@
+//! Clones in the bodies of closures and coroutines. Their signatures take
+//! no lifetime parameters, so a clone there is replaced by a borrow where it
+//! is made: `move || { let d = data.clone(); d.len() }` becomes
+//! `move || { let d = &data; d.len() }`.
+
+use crate::borrowed;
+use crate::copy;
+use crate::rewrite::{Rejection, Substitutes};
+use crate::sink;
+use rustc_hir::{def_id::LocalDefId, ExprKind};
+use rustc_middle::mir::{BasicBlock, Body, Local, ProjectionElem, TerminatorKind};
+use rustc_middle::ty::{self, TyCtxt, UpvarCapture};
+
+/// The closure itself, or a reference to it, in the body of a closure.
+const ENV: Local = Local::from_u32(1);
+
+/// Edits borrowing the value cloned by the call terminating `block` of the
+/// closure `def_id`, or why the clone has to be kept.
+pub(crate) fn borrow_in_place<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+    block: BasicBlock,
+) -> Result<Substitutes, Rejection> {
+    let TerminatorKind::Call {
+        args,
+        destination,
+        fn_span,
+        ..
+    } = &body.basic_blocks[block].terminator().kind
+    else {
+        return Err(Rejection::CloneConsumed);
+    };
+    let clone = destination.as_local().ok_or(Rejection::CloneConsumed)?;
+    if let Some(reason) = sink::required(ctx, def_id, body, clone) {
+        return Err(reason);
+    }
+    if borrowed::escapes(body, clone) {
+        return Err(Rejection::CloneConsumed);
+    }
+    let borrowed = borrowed::borrowed_place(body, block, &args[0].node);
+    if let Some(place) = borrowed.filter(|place| place.local == ENV) {
+        // a captured variable: a borrow of it is as mutable as the capture
+        let field = place.projection.iter().find_map(|elem| match elem {
+            ProjectionElem::Field(field, _) => Some(field),
+            _ => None,
+        });
+        let capture = field.and_then(|field| ctx.closure_captures(def_id).get(field.index()));
+        if let Some(capture) = capture {
+            if let UpvarCapture::ByRef(ty::BorrowKind::Mutable | ty::BorrowKind::UniqueImmutable) =
+                capture.info.capture_kind
+            {
+                log::debug!(
+                    "{} is captured by a mutable borrow",
+                    capture.to_string(*ctx)
+                );
+                return Err(Rejection::SourceMutablyBorrowed);
+            }
+        }
+    }
+    let expr = copy::call_at(ctx, def_id, *fn_span).ok_or(Rejection::CloneConsumed)?;
+    let (lo, hi) = (expr.span.lo().0, expr.span.hi().0);
+    // `x.clone().len()` is `(&x).len()`, not `&x.len()`
+    let (open, close) = if copy::is_postfix_operand(ctx, expr) {
+        ("(", ")")
+    } else {
+        ("", "")
+    };
+    let mut s = Substitutes::new();
+    match expr.kind {
+        // a receiver is borrowed automatically, unless it is a reference
+        ExprKind::MethodCall(_, receiver, [], _) => {
+            let is_ref = ctx.typeck(def_id).expr_ty(receiver).is_ref();
+            if borrowed.is_some() && !is_ref {
+                let start = receiver.span.lo().0;
+                s.rewrite(start, start, format!("{open}&"));
+                s.rewrite(receiver.span.hi().0, hi, close.to_owned());
+            } else {
+                s.rewrite(receiver.span.hi().0, hi, "".to_owned());
+            }
+        }
+        // `Clone::clone(&x)`
+        ExprKind::Call(_, [arg]) => {
+            s.rewrite(lo, arg.span.lo().0, open.to_owned());
+            s.rewrite(arg.span.hi().0, hi, close.to_owned());
+        }
+        _ => return Err(Rejection::CloneConsumed),
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Rejection;
+    use crate::Options;
+
+    const PATH: &str = "closure_fixture.rs";
+
+    #[test]
+    fn borrowed_in_place() {
+        let source = "fn f(data: Vec<u8>) -> usize {\n    let c = move || {\n        let d = data.clone();\n        d.len() + data.len()\n    };\n    c()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn f(data: Vec<u8>) -> usize {\n    let c = move || {\n        let d = &data;\n        d.len() + data.len()\n    };\n    c()\n}"
+        );
+    }
+
+    #[test]
+    fn borrowed_receiver() {
+        // `&data.len()` would borrow the length
+        let source = "fn f(data: Vec<u8>) -> usize {\n    let c = move || data.clone().len();\n    c()\n}\n\
+                      fn g(data: &Vec<u8>) -> usize {\n    let c = || data.clone().len();\n    c()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn f(data: Vec<u8>) -> usize {\n    let c = move || (&data).len();\n    c()\n}\n\
+             fn g(data: &Vec<u8>) -> usize {\n    let c = || data.len();\n    c()\n}"
+        );
+    }
+
+    #[test]
+    fn mutable_capture() {
+        let source = "fn f(mut data: Vec<u8>) -> usize {\n    let mut c = || {\n        let d = data.clone();\n        data.push(1);\n        d.len()\n    };\n    c()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(reasons, [&Rejection::SourceMutablyBorrowed]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Calls producing an owned value out of a borrowed one, which can be
+//! removed by borrowing instead: `clone`, `to_owned`, `to_string`, ...
+
+use rustc_hir::{def_id::DefId, LangItem};
+use rustc_middle::ty::{Ty, TyCtxt, TyKind};
+use rustc_span::sym;
+
+/// How the value of a removed conversion is borrowed instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Borrow {
+    /// `&T` for a `T`
+    Ref,
+    /// the deref target: `&str` for a `String`, `&[T]` for a `Vec<T>`, else `&T`
+    Deref,
+}
+
+/// A function treated as a clone, besides the ones of the standard library
+/// known by [`Conversions::find`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Conversion {
+    /// def path of the function, e.g. `mycrate::Name::to_owned_name`
+    pub path: String,
+    pub borrow: Borrow,
+}
+impl Conversion {
+    pub fn new(path: &str, borrow: Borrow) -> Self {
+        Self {
+            path: path.to_owned(),
+            borrow,
+        }
+    }
+}
+
+/// The owning conversions to eliminate: `Clone::clone`, `ToOwned::to_owned`,
+/// `ToString::to_string`, `<[T]>::to_vec` and `From::from`, found by their
+/// lang and diagnostic items as `core` or `std` may define them, and the
+/// user-configured ones of the table.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Conversions(pub Vec<Conversion>);
+impl Conversions {
+    /// How the result of a call to `def_id` is borrowed instead, if it is
+    /// one of the conversions.
+    pub fn find(&self, ctx: &TyCtxt<'_>, def_id: DefId) -> Option<Borrow> {
+        if ctx.is_lang_item(def_id, LangItem::CloneFn) {
+            return Some(Borrow::Ref);
+        }
+        let deref = [sym::to_owned_method, sym::to_string_method, sym::from_fn];
+        if deref
+            .iter()
+            .any(|name| ctx.is_diagnostic_item(*name, def_id))
+            || is_slice_to_vec(ctx, def_id)
+        {
+            return Some(Borrow::Deref);
+        }
+        let path = ctx.def_path_str(def_id);
+        self.0
+            .iter()
+            .find(|c| c.path == path)
+            .map(|conversion| conversion.borrow)
+    }
+    /// How the result of calling `def_id` on a `from` is borrowed instead,
+    /// if the call is a conversion producing a `to` out of it. `from` may be
+    /// behind references, so that `n.to_string()` on an integer is not a
+    /// conversion but `s.to_string()` on a `&&str` is, and `to` may be one
+    /// of them, as for `r.clone()` on a `&&T`.
+    pub fn borrow<'tcx>(
+        &self,
+        ctx: &TyCtxt<'tcx>,
+        def_id: DefId,
+        from: Ty<'tcx>,
+        to: Ty<'tcx>,
+    ) -> Option<Borrow> {
+        let borrow = self.find(ctx, def_id)?;
+        let to = ctx.erase_regions(to);
+        // down to the reference itself, cloned by `<&T as Clone>::clone`, but
+        // through one at least: the identity `From::from` converts nothing
+        let mut derefs =
+            std::iter::successors(Some(ctx.erase_regions(from)), |ty| match ty.kind() {
+                TyKind::Ref(_, inner, _) => Some(*inner),
+                _ => None,
+            })
+            .skip(1);
+        let from = ctx.erase_regions(from.peel_refs());
+        let converts = derefs.any(|ty| ty == to)
+            || (borrow == Borrow::Deref
+                && match owned(ctx, to) {
+                    Owned::String => from.is_str(),
+                    Owned::Vec => {
+                        from.is_slice() && Some(from.sequence_element_type(*ctx)) == elem(to)
+                    }
+                    Owned::Box | Owned::Shared | Owned::Option | Owned::Other => false,
+                });
+        converts.then_some(borrow)
+    }
+}
+
+/// Whether `def_id` is `to_vec` of the inherent impl of slices.
+fn is_slice_to_vec(ctx: &TyCtxt<'_>, def_id: DefId) -> bool {
+    ctx.item_name(def_id) == sym::to_vec
+        && ctx.impl_of_method(def_id).is_some_and(|imp| {
+            ctx.trait_id_of_impl(imp).is_none()
+                && ctx.type_of(imp).instantiate_identity().is_slice()
+        })
+}
+
+/// Owned types with a borrowed form other than a plain reference.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Owned {
+    String,
+    Vec,
+    Box,
+    /// `Rc` or `Arc`
+    Shared,
+    Option,
+    Other,
+}
+
+pub(crate) fn owned(ctx: &TyCtxt<'_>, ty: Ty<'_>) -> Owned {
+    match ty.ty_adt_def() {
+        Some(adt) if ctx.is_lang_item(adt.did(), LangItem::String) => Owned::String,
+        Some(adt) if ctx.is_diagnostic_item(sym::Vec, adt.did()) => Owned::Vec,
+        Some(adt) if adt.is_box() => Owned::Box,
+        Some(adt)
+            if ctx.is_diagnostic_item(sym::Rc, adt.did())
+                || ctx.is_diagnostic_item(sym::Arc, adt.did()) =>
+        {
+            Owned::Shared
+        }
+        Some(adt) if ctx.is_lang_item(adt.did(), LangItem::Option) => Owned::Option,
+        _ => Owned::Other,
+    }
+}
+
+/// The element type of a `Vec`.
+fn elem(vec: Ty<'_>) -> Option<Ty<'_>> {
+    match vec.kind() {
+        rustc_middle::ty::Adt(_, args) => args.types().next(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::Options;
+
+    const PATH: &str = "conversion_fixture.rs";
+
+    #[test]
+    fn standard() {
+        let source = "fn owned(s: String) -> usize {\n    let t = s.to_owned();\n    t.len() + s.len()\n}\n\
+                      fn string(s: String) -> usize {\n    let t = s.to_string();\n    t.len() + s.len()\n}\n\
+                      fn from(s: &str) -> usize {\n    let t = String::from(s);\n    t.len() + s.len()\n}\n\
+                      fn vec(v: Vec<u8>) -> usize {\n    let w = v.to_vec();\n    w.len() + v.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn owned(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}\n\
+             fn string(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}\n\
+             fn from(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}\n\
+             fn vec(v: &[u8]) -> usize {\n    let w = v;\n    w.len() + v.len()\n}"
+        );
+    }
+
+    #[test]
+    fn formatted_number() {
+        // a new string, not a copy of `n`
+        let source = "fn f(n: u32) -> usize {\n    let s = n.to_string();\n    s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        assert!(rewrite.rejected.is_empty(), "{:?}", rewrite.rejected);
+    }
+
+    #[test]
+    fn configured() {
+        let source = "struct Name(String);\n\
+                      impl Name {\n    fn dup(&self) -> Name {\n        Name(self.0.clone())\n    }\n}\n\
+                      fn f(n: Name) -> usize {\n    let m = n.dup();\n    m.0.len() + n.0.len()\n}";
+        let options = Options {
+            conversions: Conversions(vec![Conversion::new("Name::dup", Borrow::Ref)]),
+            ..Options::default()
+        };
+        let rewrite = fixture::rewrite(PATH, source, "f", &options);
+        assert!(fixture::rewritten(&rewrite)
+            .ends_with("fn f(n: &Name) -> usize {\n    let m = n;\n    m.0.len() + n.0.len()\n}"));
+    }
+}
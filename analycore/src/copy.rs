@@ -0,0 +1,156 @@
+//! Clones of `Copy` values, e.g. `n.clone()` on a `u32` or `r.clone()` on
+//! a `&T` reference, replaced by a plain copy: `n`, `*r`.
+
+use crate::rewrite::Substitutes;
+use rustc_hir::{
+    def_id::LocalDefId,
+    intravisit::{self, Visitor},
+    BorrowKind, Expr, ExprKind, Mutability, Node,
+};
+use rustc_middle::ty::{Ty, TyCtxt, TyKind};
+use rustc_span::Span;
+
+/// Edits turning the clone called at `fn_span` in the body of `def_id` into
+/// a copy of the cloned value, dereferenced as needed.
+pub(crate) fn copy(ctx: &TyCtxt<'_>, def_id: LocalDefId, fn_span: Span) -> Option<Substitutes> {
+    let expr = call_at(ctx, def_id, fn_span)?;
+    let typeck = ctx.typeck(def_id);
+    let to = typeck.expr_ty(expr);
+    let mut s = Substitutes::new();
+    let (lo, hi) = (expr.span.lo().0, expr.span.hi().0);
+    // where the copied value starts, and how many times it is dereferenced
+    let (start, derefs) = match expr.kind {
+        // `x.clone()`
+        ExprKind::MethodCall(_, receiver, [], _) => {
+            let derefs = derefs(ctx, typeck.expr_ty(receiver), to)?;
+            s.rewrite(receiver.span.hi().0, hi, "".to_owned());
+            (receiver.span.lo().0, derefs)
+        }
+        // `Clone::clone(&x)`
+        ExprKind::Call(_, [arg]) => {
+            let (arg, derefs) = match arg.kind {
+                ExprKind::AddrOf(BorrowKind::Ref, Mutability::Not, inner) => {
+                    (inner, derefs(ctx, typeck.expr_ty(inner), to)?)
+                }
+                _ => (arg, derefs(ctx, typeck.expr_ty(arg), to)?),
+            };
+            s.rewrite(lo, arg.span.lo().0, "".to_owned());
+            s.rewrite(arg.span.hi().0, hi, "".to_owned());
+            (arg.span.lo().0, derefs)
+        }
+        _ => return None,
+    };
+    if derefs == 0 {
+        return Some(s);
+    }
+    let stars = "*".repeat(derefs);
+    if is_postfix_operand(ctx, expr) {
+        // `r.clone().f()` is `(*r).f()`, not `*r.f()`
+        s.rewrite(start, start, format!("({stars}"));
+        s.rewrite(hi, hi, ")".to_owned());
+    } else {
+        s.rewrite(start, start, stars);
+    }
+    Some(s)
+}
+
+/// The call at `fn_span` in the body of `def_id`.
+pub(crate) fn call_at<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    fn_span: Span,
+) -> Option<&'tcx Expr<'tcx>> {
+    let mut find = Find {
+        fn_span,
+        found: None,
+    };
+    find.visit_body(ctx.hir().body_owned_by(def_id));
+    find.found
+}
+
+/// How many references have to be dereferenced to get a `to` out of a `from`.
+fn derefs<'tcx>(ctx: &TyCtxt<'tcx>, mut from: Ty<'tcx>, to: Ty<'tcx>) -> Option<usize> {
+    let to = ctx.erase_regions(to);
+    let mut derefs = 0;
+    while ctx.erase_regions(from) != to {
+        let TyKind::Ref(_, inner, _) = from.kind() else {
+            return None;
+        };
+        from = *inner;
+        derefs += 1;
+    }
+    Some(derefs)
+}
+
+/// Whether `expr` is the operand of a method call, a field access, an index
+/// or a call, which bind tighter than a dereference.
+pub(crate) fn is_postfix_operand(ctx: &TyCtxt<'_>, expr: &Expr<'_>) -> bool {
+    let Node::Expr(parent) = ctx.parent_hir_node(expr.hir_id) else {
+        return false;
+    };
+    match parent.kind {
+        ExprKind::MethodCall(_, operand, ..)
+        | ExprKind::Field(operand, _)
+        | ExprKind::Index(operand, ..)
+        | ExprKind::Call(operand, _) => operand.hir_id == expr.hir_id,
+        _ => false,
+    }
+}
+
+/// The call expression at `fn_span`, the span MIR gives to a call: the
+/// method name and arguments of a method call, the whole of other calls.
+struct Find<'tcx> {
+    fn_span: Span,
+    found: Option<&'tcx Expr<'tcx>>,
+}
+impl<'tcx> Visitor<'tcx> for Find<'tcx> {
+    fn visit_expr(&mut self, expr: &'tcx Expr<'tcx>) {
+        let span = match expr.kind {
+            ExprKind::MethodCall(_, _, _, span) => span,
+            ExprKind::Call(..) => expr.span,
+            _ => {
+                intravisit::walk_expr(self, expr);
+                return;
+            }
+        };
+        if span == self.fn_span {
+            self.found = Some(expr);
+        } else {
+            intravisit::walk_expr(self, expr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Strategy;
+    use crate::Options;
+
+    const PATH: &str = "copy_fixture.rs";
+
+    #[test]
+    fn copied() {
+        let source = "fn n(n: u32) -> u32 {\n    n.clone() + 1\n}\n\
+                      fn r(r: &String) -> usize {\n    let s: &String = Clone::clone(&r);\n    s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        let strategies: Vec<Strategy> = rewrite.eliminations.iter().map(|e| e.strategy).collect();
+        assert_eq!(strategies, [Strategy::Copy, Strategy::Copy]);
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn n(n: u32) -> u32 {\n    n + 1\n}\n\
+             fn r(r: &String) -> usize {\n    let s: &String = r;\n    s.len()\n}"
+        );
+    }
+
+    #[test]
+    fn dereferenced_receiver() {
+        // `*r.pow(2)` would dereference the power
+        let source = "fn f(r: &u32) -> u32 {\n    r.clone().pow(2)\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn f(r: &u32) -> u32 {\n    (*r).pow(2)\n}"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! The eliminations of a file as a unified diff, reviewed before they are
+//! applied and applied with `git apply` or `patch -p1`.
+
+use crate::rewrite::{EditError, Substitutes};
+
+/// lines of unchanged context around each hunk
+const CONTEXT: usize = 3;
+
+/// Changed lines `first..=last` of the original source and their new text.
+struct Block {
+    first: usize,
+    last: usize,
+    new: String,
+}
+
+/// Replacements on lines `first..=last`, the ones on a line or the next.
+type Group = (usize, usize, Vec<(u32, u32, String)>);
+
+/// Renders `substitutes` on `source` as a unified diff of `path`, suitable
+/// for `git apply`, if they can be applied to it.
+pub fn unified(path: &str, source: &str, substitutes: &Substitutes) -> Result<String, EditError> {
+    let edits = substitutes.validate(source)?;
+    let starts: Vec<usize> = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .filter(|i| *i < source.len())
+        .collect();
+    if starts.is_empty() {
+        return Ok(String::new());
+    }
+    let line_of = |offset: usize| starts.partition_point(|s| *s <= offset).max(1) - 1;
+    let line_end = |line: usize| starts.get(line + 1).copied().unwrap_or(source.len());
+
+    // group the replacements touching the same or adjacent lines
+    let mut groups: Vec<Group> = Vec::new();
+    for (from, until, insert) in edits {
+        let first = line_of(from as usize);
+        // a replacement ending with a newline ends on the line of the newline
+        let end = match until as usize {
+            end if from < until && source.as_bytes()[end - 1] == b'\n' => end - 1,
+            end => end,
+        };
+        let last = line_of(end).max(first);
+        match groups.last_mut() {
+            Some((_, glast, edits)) if first <= *glast + 1 => {
+                *glast = last.max(*glast);
+                edits.push((from, until, insert));
+            }
+            _ => groups.push((first, last, vec![(from, until, insert)])),
+        }
+    }
+    let blocks: Vec<Block> = groups
+        .into_iter()
+        .map(|(first, last, edits)| {
+            let base = starts[first];
+            let mut new = String::new();
+            let mut pos = base;
+            for (from, until, insert) in edits {
+                new.push_str(&source[pos..from as usize]);
+                new.push_str(&insert);
+                pos = until as usize;
+            }
+            new.push_str(&source[pos..line_end(last)]);
+            Block { first, last, new }
+        })
+        .collect();
+
+    let mut out = format!("--- a/{path}\n+++ b/{path}\n");
+    let mut delta = 0isize;
+    let mut i = 0;
+    while i < blocks.len() {
+        // blocks whose contexts overlap share a hunk
+        let mut j = i;
+        while j + 1 < blocks.len() && blocks[j + 1].first <= blocks[j].last + 2 * CONTEXT + 1 {
+            j += 1;
+        }
+        let first = blocks[i].first.saturating_sub(CONTEXT);
+        let last = (blocks[j].last + CONTEXT).min(starts.len() - 1);
+        let mut body = String::new();
+        let (mut old_len, mut new_len) = (0, 0);
+        let mut line = first;
+        for block in &blocks[i..=j] {
+            for l in line..block.first {
+                push_line(&mut body, ' ', &source[starts[l]..line_end(l)]);
+                old_len += 1;
+                new_len += 1;
+            }
+            for l in block.first..=block.last {
+                push_line(&mut body, '-', &source[starts[l]..line_end(l)]);
+                old_len += 1;
+            }
+            for l in block.new.split_inclusive('\n') {
+                push_line(&mut body, '+', l);
+                new_len += 1;
+            }
+            line = block.last + 1;
+        }
+        for l in line..=last {
+            push_line(&mut body, ' ', &source[starts[l]..line_end(l)]);
+            old_len += 1;
+            new_len += 1;
+        }
+        let new_first = first as isize + delta;
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            first + 1,
+            old_len,
+            new_first + 1,
+            new_len
+        ));
+        out.push_str(&body);
+        delta += new_len as isize - old_len as isize;
+        i = j + 1;
+    }
+    Ok(out)
+}
+
+fn push_line(out: &mut String, mark: char, line: &str) {
+    out.push(mark);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+
+    /// The diff of `source` with `edits`, given as `(text, replacement)`
+    /// by the first occurrence of the text.
+    fn diff(source: &str, edits: &[(&str, &str)]) -> String {
+        let mut s = Substitutes::new();
+        for (text, insert) in edits {
+            let from = source.find(text).unwrap() as u32;
+            s.rewrite(from, from + text.len() as u32, insert.to_string());
+        }
+        unified("f.rs", source, &s).unwrap()
+    }
+
+    #[test]
+    fn no_final_newline() {
+        let source = "let a = 1;\nlet b = a.clone();";
+        assert_eq!(
+            diff(source, &[(".clone()", "")]),
+            "--- a/f.rs\n+++ b/f.rs\n@@ -1,2 +1,2 @@\n let a = 1;\n\
+             -let b = a.clone();\n\\ No newline at end of file\n\
+             +let b = a;\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn adjacent_hunks() {
+        let source = (1..=20).fold(String::new(), |mut source, i| {
+            writeln!(source, "l{i}").unwrap();
+            source
+        });
+        // l2 and l3 are one block, l10 shares their hunk as the contexts
+        // meet, l18 is too far for it
+        let edits = [
+            ("l2\n", "m2\n"),
+            ("l3\n", "m3\n"),
+            ("l10\n", "m10\n"),
+            ("l18\n", "m18\n"),
+        ];
+        assert_eq!(
+            diff(&source, &edits),
+            "--- a/f.rs\n+++ b/f.rs\n\
+             @@ -1,13 +1,13 @@\n l1\n-l2\n-l3\n+m2\n+m3\n l4\n l5\n l6\n l7\n l8\n l9\n-l10\n+m10\n l11\n l12\n l13\n\
+             @@ -15,6 +15,6 @@\n l15\n l16\n l17\n-l18\n+m18\n l19\n l20\n"
+        );
+    }
+
+    #[test]
+    fn multi_line_edit() {
+        let source = "f(\n    a,\n    b,\n);\nx\nx\nx\nx\nx\nx\nx\ng(c.clone());\n";
+        // the next hunk starts three lines earlier in the new source
+        assert_eq!(
+            diff(
+                source,
+                &[("(\n    a,\n    b,\n)", "(a, b)"), (".clone()", "")]
+            ),
+            "--- a/f.rs\n+++ b/f.rs\n\
+             @@ -1,7 +1,4 @@\n-f(\n-    a,\n-    b,\n-);\n+f(a, b);\n x\n x\n x\n\
+             @@ -9,4 +6,4 @@\n x\n x\n x\n-g(c.clone());\n+g(c);\n"
+        );
+    }
+}
@@ -1,30 +1,43 @@
-use crate::rewrite::rewrite;
-use rustc_hir::{def_id::DefId, Item, ItemKind};
+use crate::check;
+use rustc_data_structures::sync::Lrc;
+use rustc_driver::{Callbacks, Compilation, RunCompiler};
+use rustc_hir::{
+    def::DefKind,
+    def_id::{LocalDefId, LOCAL_CRATE},
+};
 use rustc_interface::interface;
 use rustc_interface::interface::Compiler;
+use rustc_interface::Queries;
 use rustc_middle::ty::TyCtxt;
-use rustc_session::config;
-use rustc_span::{FileName, RealFileName};
+use rustc_session::{config, EarlyDiagCtxt};
+use rustc_span::edition::Edition;
+use rustc_span::source_map::{FileLoader, RealFileLoader};
+use rustc_span::{FileName, Span};
 use std::collections::HashMap;
+use std::io;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{atomic::AtomicBool, Arc, Mutex};
 
 use crate::{CompileResult, Error};
 
 pub fn enter<T>(
     name: PathBuf,
-    source: String,
+    sources: HashMap<PathBuf, String>,
     f: impl for<'tcx> FnOnce(&TyCtxt<'tcx>) -> Result<T, Error> + Send + panic::UnwindSafe,
 ) -> Result<CompileResult<Result<T, Error>>, Error>
 where
     T: Send,
 {
     let res = Arc::new(Mutex::new(None));
+    let diagnostics = check::Diagnostics::default();
     let compile_err = panic::catch_unwind(|| {
         let res = res.clone();
+        let diagnostics = diagnostics.clone();
         let config = interface::Config {
             opts: config::Options {
+                // as `cargo new` does, for `async fn` and such
+                edition: Edition::Edition2021,
                 optimize: config::OptLevel::No,
                 debuginfo: config::DebugInfo::Full,
                 unstable_opts: config::UnstableOptions {
@@ -33,13 +46,10 @@ where
                 },
                 ..Default::default()
             },
-            input: config::Input::Str {
-                name: FileName::Real(RealFileName::LocalPath(name)),
-                input: source,
-            },
+            input: config::Input::File(name),
             output_dir: None,
             output_file: None,
-            file_loader: None,
+            file_loader: Some(Box::new(Loader { sources })),
             lint_caps: HashMap::default(),
             register_lints: None,
             override_queries: None,
@@ -51,7 +61,7 @@ where
             ice_file: None,
             locale_resources: rustc_driver::DEFAULT_LOCALE_RESOURCES.to_owned(),
             make_codegen_backend: None,
-            psess_created: None,
+            psess_created: Some(Box::new(move |psess| check::capture(psess, diagnostics))),
             using_internal_features: Arc::new(AtomicBool::new(true)),
         };
         log::info!("compiler configured; start compilation");
@@ -75,6 +85,20 @@ where
         })
     })
     .is_err();
+    finish(res, diagnostics, compile_err)
+}
+
+/// Result of a compilation running `f`: the captured errors if any, else what
+/// `f` returned.
+fn finish<T>(
+    res: Arc<Mutex<Option<Result<T, Error>>>>,
+    diagnostics: check::Diagnostics,
+    compile_err: bool,
+) -> Result<CompileResult<Result<T, Error>>, Error> {
+    let diagnostics = std::mem::take(&mut *diagnostics.lock().unwrap());
+    if !diagnostics.is_empty() {
+        return Err(Error::Compile(diagnostics));
+    }
     if let Some(res) = Arc::into_inner(res).unwrap().into_inner().unwrap() {
         if compile_err {
             Ok(CompileResult::Err(res))
@@ -87,17 +111,214 @@ where
     }
 }
 
-pub fn get_fn<'a, 'tcx>(ctx: &'a TyCtxt<'tcx>, fn_name: &str) -> Option<&'a Item<'tcx>> {
-    for item_id in ctx.hir().items() {
-        let item = ctx.hir().item(item_id);
-        match item.kind {
-            ItemKind::Fn(_signature, _generics, _body_id) => {
-                if fn_name == item.ident.name.as_str() {
-                    return Some(&item);
-                }
-            }
-            _ => {}
+/// Serves `sources` in place of the files at their paths, everything else
+/// from the disk.
+struct Loader {
+    sources: HashMap<PathBuf, String>,
+}
+impl FileLoader for Loader {
+    fn file_exists(&self, path: &Path) -> bool {
+        self.sources.contains_key(path) || RealFileLoader.file_exists(path)
+    }
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        match self.sources.get(path) {
+            Some(source) => Ok(source.clone()),
+            None => RealFileLoader.read_file(path),
+        }
+    }
+    fn read_binary_file(&self, path: &Path) -> io::Result<Lrc<[u8]>> {
+        match self.sources.get(path) {
+            Some(source) => Ok(source.as_bytes().into()),
+            None => RealFileLoader.read_binary_file(path),
+        }
+    }
+}
+
+struct EnterCallbacks<F, T> {
+    sources: Option<HashMap<PathBuf, String>>,
+    f: Option<F>,
+    res: Arc<Mutex<Option<Result<T, Error>>>>,
+    diagnostics: check::Diagnostics,
+}
+impl<F, T> Callbacks for EnterCallbacks<F, T>
+where
+    F: for<'tcx> FnOnce(&TyCtxt<'tcx>) -> Result<T, Error>,
+{
+    fn config(&mut self, config: &mut interface::Config) {
+        if let Some(sources) = self.sources.take() {
+            config.file_loader = Some(Box::new(Loader { sources }));
+        }
+        let diagnostics = self.diagnostics.clone();
+        config.psess_created = Some(Box::new(move |psess| check::capture(psess, diagnostics)));
+    }
+    fn after_expansion<'tcx>(
+        &mut self,
+        _compiler: &interface::Compiler,
+        queries: &'tcx Queries<'tcx>,
+    ) -> Compilation {
+        log::info!("entered into compiler");
+        let Ok(mut gcx) = queries.global_ctxt() else {
+            log::error!("error on fetching global context");
+            *self.res.lock().unwrap() = Some(Err(Error::Internal));
+            return Compilation::Stop;
+        };
+        if let Some(f) = self.f.take() {
+            gcx.enter(|ctx| {
+                log::info!("entered into global context");
+                *self.res.lock().unwrap() = Some(f(&ctx));
+            });
+        }
+        Compilation::Stop
+    }
+}
+
+/// Same as [`enter`], but the compiler is configured from a full rustc
+/// command line (e.g. the one cargo passes to a `RUSTC_WRAPPER`).
+/// `sources` replace the contents of the files at their paths.
+pub fn enter_with_args<T>(
+    args: &[String],
+    sources: HashMap<PathBuf, String>,
+    f: impl for<'tcx> FnOnce(&TyCtxt<'tcx>) -> Result<T, Error> + Send + panic::UnwindSafe,
+) -> Result<CompileResult<Result<T, Error>>, Error>
+where
+    T: Send,
+{
+    let res = Arc::new(Mutex::new(None));
+    let diagnostics = check::Diagnostics::default();
+    let compile_err = panic::catch_unwind(|| {
+        let mut callbacks = EnterCallbacks {
+            sources: Some(sources),
+            f: Some(f),
+            res: res.clone(),
+            diagnostics: diagnostics.clone(),
+        };
+        log::info!("compiler configured by arguments; start compilation");
+        RunCompiler::new(args, &mut callbacks).run().is_err()
+    })
+    .unwrap_or(true);
+    finish(res, diagnostics, compile_err)
+}
+
+/// Finds the crate root in a rustc command line.
+pub fn crate_root(args: &[String]) -> Option<PathBuf> {
+    let early_dcx = EarlyDiagCtxt::new(config::ErrorOutputType::default());
+    let matches = rustc_driver::handle_options(&early_dcx, args.get(1..)?)?;
+    match matches.free.as_slice() {
+        [root] if root != "-" => Some(PathBuf::from(root)),
+        _ => None,
+    }
+}
+
+/// Whether `span` points into a source file of the crate on disk, which can
+/// be rewritten.
+pub fn in_source_file(ctx: &TyCtxt<'_>, span: Span) -> bool {
+    let file = ctx.sess.source_map().lookup_source_file(span.lo());
+    let on_disk = matches!(&file.name, FileName::Real(name) if name.local_path().is_some());
+    on_disk && file.cnum == LOCAL_CRATE
+}
+
+/// Function-like bodies written in the crate: free functions, inherent and
+/// trait impl methods and provided trait methods. Bodies generated by macros
+/// (e.g. derives) are skipped since there is no source to rewrite.
+pub fn fn_bodies<'tcx>(ctx: &TyCtxt<'tcx>) -> impl Iterator<Item = LocalDefId> + 'tcx {
+    let ctx = *ctx;
+    ctx.hir().body_owners().filter(move |def_id| {
+        matches!(ctx.def_kind(*def_id), DefKind::Fn | DefKind::AssocFn)
+            && !ctx.def_span(*def_id).from_expansion()
+    })
+}
+
+/// The closures and coroutines in the body of `def_id`, nested ones included.
+pub fn nested_bodies<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+) -> impl Iterator<Item = LocalDefId> + 'tcx {
+    let ctx = *ctx;
+    ctx.hir().body_owners().filter(move |owner| {
+        *owner != def_id
+            && ctx.is_closure_like(owner.to_def_id())
+            && ctx.typeck_root_def_id(owner.to_def_id()) == def_id.to_def_id()
+    })
+}
+
+/// Finds a function-like body by its def path: a free function
+/// (`net::codec::parse`), an inherent method (`Type::method`), a trait impl
+/// method (`<Type as Trait>::method`) or a provided trait method
+/// (`Trait::method`). A leading `crate::` is optional, and any trailing part
+/// of the path (e.g. the bare name) is accepted as long as it is unambiguous.
+pub fn get_fn(ctx: &TyCtxt<'_>, fn_name: &str) -> Result<LocalDefId, Error> {
+    let fn_name = fn_name.strip_prefix("crate::").unwrap_or(fn_name);
+    let mut candidates = Vec::new();
+    for def_id in fn_bodies(ctx) {
+        let path = ctx.def_path_str(def_id);
+        let path = path.strip_prefix("crate::").unwrap_or(&path).to_owned();
+        if path == fn_name {
+            return Ok(def_id);
         }
+        if path
+            .strip_suffix(fn_name)
+            .is_some_and(|prefix| prefix.ends_with("::"))
+        {
+            candidates.push((def_id, path));
+        }
+    }
+    match candidates.len() {
+        0 => Err(Error::FnNotFound),
+        1 => Ok(candidates[0].0),
+        _ => Err(Error::AmbiguousFn(
+            candidates.into_iter().map(|(_, path)| path).collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "mod a {\n    pub fn parse() {}\n}\n\
+                          mod b {\n    pub fn parse() {}\n}\n\
+                          trait T {\n    fn m(&self);\n}\n\
+                          struct S;\n\
+                          impl T for S {\n    fn m(&self) {}\n}\n\
+                          impl S {\n    fn own(&self) {}\n}\n\
+                          fn main() {}\n";
+
+    /// The def path of the function `name` resolves to, or why it does not.
+    fn resolve(name: &str) -> Result<String, Error> {
+        let path = PathBuf::from("enter_fixture.rs");
+        let sources = HashMap::from([(path.clone(), SOURCE.to_owned())]);
+        let res = enter(path, sources, |ctx| {
+            Ok(get_fn(ctx, name).map(|def_id| ctx.def_path_str(def_id)))
+        });
+        let Ok(CompileResult::Ok(Ok(res))) = res else {
+            panic!("{res:?}");
+        };
+        res
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(resolve("a::parse"), Ok("a::parse".to_owned()));
+        assert_eq!(resolve("crate::b::parse"), Ok("b::parse".to_owned()));
+        assert_eq!(resolve("<S as T>::m"), Ok("<S as T>::m".to_owned()));
+        assert_eq!(resolve("S::own"), Ok("S::own".to_owned()));
+        assert_eq!(resolve("own"), Ok("S::own".to_owned()));
+    }
+
+    #[test]
+    fn ambiguous() {
+        assert_eq!(
+            resolve("parse"),
+            Err(Error::AmbiguousFn(vec![
+                "a::parse".to_owned(),
+                "b::parse".to_owned()
+            ]))
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        assert_eq!(resolve("no_such_fn"), Err(Error::FnNotFound));
+        assert_eq!(resolve("c::parse"), Err(Error::FnNotFound));
     }
-    None
 }
@@ -0,0 +1,477 @@
+//! Opt-in: owned fields that are always initialized by a clone become
+//! borrows, and their struct takes a lifetime parameter. `struct Person {
+//! name: String }` built as `Person { name: name.to_string() }` becomes
+//! `struct Person<'a> { name: &'a str }` built as `Person { name }`, with
+//! `impl Person` turned into `impl<'a> Person<'a>` and the other mentions
+//! of `Person` in types into `Person<'_>`. A struct named in the type of a
+//! field or another item, where `'_` is not allowed, keeps its fields.
+
+use crate::borrowed::Form;
+use crate::conversion::{owned, Conversions, Owned};
+use crate::enter;
+use crate::generics::ParamList;
+use crate::lifetime::{self, Lifetimes, Scheme};
+use crate::rewrite::{Elimination, Range, Rejected, Rejection, Strategy, Substitutes};
+use rustc_hir::{
+    def::{DefKind, Res},
+    def_id::LocalDefId,
+    intravisit::{self, Visitor},
+    Expr, ExprKind, Generics, HirId, ImplItemKind, Item, ItemKind, LifetimeSuggestionPosition,
+    Node, OwnerNode, Path, QPath, TraitItemKind, Ty, TyKind, VariantData,
+};
+use rustc_middle::hir::nested_filter;
+use rustc_middle::ty::{TyCtxt, TypeVisitableExt, TypeckResults, TypingEnv};
+use rustc_span::{symbol::Ident, Span};
+use std::collections::{HashMap, HashSet};
+
+/// One elimination per struct of the crate whose fields can borrow, the
+/// structs whose fields could but are kept, and the spans of the field
+/// initializers the eliminations rewrite.
+pub(crate) fn eliminations(
+    ctx: &TyCtxt<'_>,
+    conversions: &Conversions,
+    scheme: &Scheme,
+) -> (Vec<Elimination>, Vec<Rejected>, Vec<Range>) {
+    let mut literals = Literals {
+        ctx: *ctx,
+        typeck: None,
+        inits: HashMap::new(),
+        based: HashSet::new(),
+    };
+    for owner in ctx.hir().body_owners() {
+        if ctx.is_typeck_child(owner.to_def_id()) {
+            continue;
+        }
+        literals.typeck = Some(ctx.typeck(owner));
+        literals.visit_body(ctx.hir().body_owned_by(owner));
+    }
+    let mut eliminations = Vec::new();
+    let mut rejected = Vec::new();
+    let mut claimed = Vec::new();
+    for item_id in ctx.hir().items() {
+        let item = ctx.hir().item(item_id);
+        let ItemKind::Struct(VariantData::Struct { fields, .. }, generics) = item.kind else {
+            continue;
+        };
+        let def_id = item.owner_id.def_id;
+        if item.span.from_expansion()
+            || !enter::in_source_file(ctx, item.span)
+            || literals.based.contains(&def_id)
+        {
+            continue;
+        }
+        let Some(inits) = literals.inits.get(&def_id) else {
+            continue;
+        };
+        let impls = impls_of(ctx, def_id);
+        // the lifetime is in scope in the impls and their items too
+        let mut scope = vec![OwnerNode::Item(item)];
+        for (_, self_ty) in &impls {
+            let imp = ctx.hir().expect_item(self_ty.owner.def_id);
+            scope.push(OwnerNode::Item(imp));
+            if let ItemKind::Impl(imp) = imp.kind {
+                scope.extend(
+                    imp.items
+                        .iter()
+                        .map(|item| OwnerNode::ImplItem(ctx.hir().impl_item(item.id))),
+                );
+            }
+        }
+        let Ok(lifetime) = Lifetimes::new(scheme, lifetime::declared(scope)).fresh() else {
+            log::debug!("no lifetime name left for {def_id:?}");
+            continue;
+        };
+        let mut s = Substitutes::new();
+        let mut borrowed = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            let ty = ctx.type_of(field.def_id).instantiate_identity();
+            let form = match owned(ctx, ty) {
+                Owned::String => Form::Str,
+                Owned::Vec => Form::Slice,
+                Owned::Other if !ty.is_ref() && !ty.has_param() => Form::Ref,
+                _ => continue,
+            };
+            if ty.is_copy_modulo_regions(*ctx, TypingEnv::non_body_analysis(*ctx, def_id)) {
+                continue;
+            }
+            let sites: Vec<_> = inits.iter().filter(|init| init.field == i).collect();
+            let edits: Option<Vec<_>> = sites
+                .iter()
+                .map(|init| borrow_init(ctx, init, conversions, &impls, &lifetime))
+                .collect();
+            let (true, Some(edits)) = (!sites.is_empty(), edits) else {
+                continue;
+            };
+            let Ok(snippet) = ctx.sess.source_map().span_to_snippet(field.ty.span) else {
+                continue;
+            };
+            let retype = form
+                .retype(&lifetime, &snippet)
+                .unwrap_or_else(|| format!("&{lifetime} {snippet}"));
+            let range = Range::from(field.ty.span);
+            s.rewrite(range.lo, range.hi, retype);
+            for edit in edits {
+                s.merge(&edit);
+            }
+            borrowed.extend(sites.iter().map(|init| Range::from(init.expr.span)));
+            log::info!("field {} borrows its value", field.ident);
+        }
+        if borrowed.is_empty() {
+            continue;
+        }
+        let (pos, param) = ParamList::of(generics).declare(&[lifetime.clone()]);
+        s.rewrite(pos, pos, param);
+        let mut mentions = Mentions {
+            ctx: *ctx,
+            def_id,
+            lifetime: &lifetime,
+            impls: &impls,
+            substitutes: &mut s,
+            in_items: false,
+        };
+        ctx.hir().walk_toplevel_module(&mut mentions);
+        if mentions.in_items {
+            log::debug!("{def_id:?} is named in the type of an item");
+            rejected.push(Rejected {
+                range: Range::from(item.ident.span),
+                reason: Rejection::NamedInItem,
+            });
+            continue;
+        }
+        claimed.extend(borrowed);
+        eliminations.push(Elimination {
+            range: Range::from(item.ident.span),
+            substitutes: s,
+            params: Vec::new(),
+            strategy: Strategy::Field,
+            group: None,
+        });
+    }
+    (eliminations, rejected, claimed)
+}
+
+/// The value of a field in a struct literal.
+struct Init<'tcx> {
+    /// index of the field
+    field: usize,
+    /// the field as named in the literal
+    name: Ident,
+    /// `name: expr`
+    span: Span,
+    expr: &'tcx Expr<'tcx>,
+    /// the body of the literal
+    owner: LocalDefId,
+    typeck: &'tcx TypeckResults<'tcx>,
+}
+
+/// The struct literals of the crate, by struct.
+struct Literals<'tcx> {
+    ctx: TyCtxt<'tcx>,
+    typeck: Option<&'tcx TypeckResults<'tcx>>,
+    inits: HashMap<LocalDefId, Vec<Init<'tcx>>>,
+    /// structs built from another value, `S { a, ..base }`
+    based: HashSet<LocalDefId>,
+}
+impl<'tcx> Visitor<'tcx> for Literals<'tcx> {
+    type NestedFilter = nested_filter::OnlyBodies;
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.ctx.hir()
+    }
+    fn visit_expr(&mut self, expr: &'tcx Expr<'tcx>) {
+        let typeck = self.typeck.unwrap();
+        // a derived impl, as of `Clone`, is derived again
+        let derived = expr.span.in_derive_expansion();
+        if let (ExprKind::Struct(_, fields, base), false) = (expr.kind, derived) {
+            let adt = typeck.expr_ty(expr).ty_adt_def();
+            if let Some(def_id) = adt.and_then(|adt| adt.did().as_local()) {
+                if base.is_some() {
+                    self.based.insert(def_id);
+                }
+                let owner = self.ctx.hir().enclosing_body_owner(expr.hir_id);
+                let inits = fields.iter().map(|field| Init {
+                    field: typeck.field_index(field.hir_id).index(),
+                    name: field.ident,
+                    span: field.span,
+                    expr: field.expr,
+                    owner,
+                    typeck,
+                });
+                self.inits.entry(def_id).or_default().extend(inits);
+            }
+        }
+        intravisit::walk_expr(self, expr);
+    }
+}
+
+/// Edits borrowing instead of converting the value of `init`, `None` if it
+/// is not a conversion: `x.clone()` becomes `&x`, or `x` for a reference,
+/// and `name: name.clone()` becomes `name`.
+/// A parameter `x: &T` of a method of the struct takes the `lifetime` of
+/// the struct.
+fn borrow_init<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    init: &Init<'tcx>,
+    conversions: &Conversions,
+    impls: &[(&Generics<'_>, HirId)],
+    lifetime: &str,
+) -> Option<Substitutes> {
+    let expr = init.expr;
+    if expr.span.from_expansion() {
+        return None;
+    }
+    let (callee, value) = match expr.kind {
+        ExprKind::MethodCall(_, receiver, [], _) => {
+            (init.typeck.type_dependent_def_id(expr.hir_id)?, receiver)
+        }
+        ExprKind::Call(callee, [arg]) => match &callee.kind {
+            ExprKind::Path(qpath) => (
+                init.typeck.qpath_res(qpath, callee.hir_id).opt_def_id()?,
+                arg,
+            ),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    conversions.borrow(
+        ctx,
+        callee,
+        init.typeck.expr_ty_adjusted(value),
+        init.typeck.expr_ty(expr),
+    )?;
+    let (lo, hi) = (expr.span.lo().0, expr.span.hi().0);
+    let (value_lo, value_hi) = (value.span.lo().0, value.span.hi().0);
+    let mut s = Substitutes::new();
+    let borrow = if init.typeck.expr_ty(value).is_ref() {
+        ""
+    } else {
+        "&"
+    };
+    let shorthand = match value.kind {
+        ExprKind::Path(QPath::Resolved(None, path)) => {
+            borrow.is_empty() && matches!(path.segments, [segment] if segment.ident == init.name)
+        }
+        _ => false,
+    };
+    if shorthand {
+        s.rewrite(init.span.lo().0, init.span.hi().0, init.name.to_string());
+    } else {
+        s.rewrite(lo, value_lo, borrow.to_owned());
+        s.rewrite(value_hi, hi, "".to_owned());
+    }
+    if let Some(param_ty) = param_ty(ctx, init.owner, value) {
+        let in_impl = matches!(ctx.def_kind(init.owner), DefKind::AssocFn)
+            && impls
+                .iter()
+                .any(|(_, impl_id)| impl_id.owner.def_id == ctx.local_parent(init.owner));
+        if let (true, TyKind::Ref(ref_lifetime, _)) = (in_impl, param_ty.kind) {
+            if let (LifetimeSuggestionPosition::Ampersand, span) =
+                ref_lifetime.suggestion_position()
+            {
+                s.rewrite(span.lo().0, span.hi().0, format!("{lifetime} "));
+            }
+        }
+    }
+    Some(s)
+}
+
+/// The declared type of the parameter of `owner` that `value` is.
+fn param_ty<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    owner: LocalDefId,
+    value: &Expr<'_>,
+) -> Option<&'tcx Ty<'tcx>> {
+    let ExprKind::Path(QPath::Resolved(None, path)) = value.kind else {
+        return None;
+    };
+    let Res::Local(id) = path.res else {
+        return None;
+    };
+    let decl = ctx.hir_node_by_def_id(owner).fn_decl()?;
+    let params = ctx.hir().body_owned_by(owner).params;
+    let i = params.iter().position(|param| param.pat.hir_id == id)?;
+    decl.inputs.get(i)
+}
+
+/// The inherent and trait impls of the struct `def_id` but derived ones,
+/// as their generics and the id of their self type.
+fn impls_of<'tcx>(ctx: &TyCtxt<'tcx>, def_id: LocalDefId) -> Vec<(&'tcx Generics<'tcx>, HirId)> {
+    ctx.hir()
+        .items()
+        .map(|item_id| ctx.hir().item(item_id))
+        .filter(|item| !item.span.in_derive_expansion())
+        .filter_map(|item| match item.kind {
+            ItemKind::Impl(imp) if names(imp.self_ty, def_id).is_some() => {
+                Some((imp.generics, imp.self_ty.hir_id))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The path of `ty` if it is the struct `def_id`.
+fn names<'tcx>(ty: &Ty<'tcx>, def_id: LocalDefId) -> Option<&'tcx Path<'tcx>> {
+    match ty.kind {
+        TyKind::Path(QPath::Resolved(None, path))
+            if path.res == Res::Def(DefKind::Struct, def_id.to_def_id()) =>
+        {
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Adds the lifetime to every mention of the struct in a type: named in the
+/// header of its impls, `'_` elsewhere.
+struct Mentions<'a, 'tcx> {
+    ctx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    lifetime: &'a str,
+    impls: &'a [(&'tcx Generics<'tcx>, HirId)],
+    substitutes: &'a mut Substitutes,
+    /// whether the struct is named where `'_` is not allowed: in the type
+    /// of a field, an alias, a constant or an associated type
+    in_items: bool,
+}
+impl Mentions<'_, '_> {
+    /// Whether `'_` is allowed in the type `ty`: in a function signature, an
+    /// impl header or a body.
+    fn elidable(&self, ty: &Ty<'_>) -> bool {
+        let in_body = self
+            .ctx
+            .hir()
+            .parent_iter(ty.hir_id)
+            .any(|(_, node)| matches!(node, Node::Expr(_) | Node::Block(_)));
+        in_body
+            || match self.ctx.hir_owner_node(ty.hir_id.owner) {
+                OwnerNode::Item(item) => matches!(item.kind, ItemKind::Fn(..) | ItemKind::Impl(_)),
+                OwnerNode::TraitItem(item) => matches!(item.kind, TraitItemKind::Fn(..)),
+                OwnerNode::ImplItem(item) => matches!(item.kind, ImplItemKind::Fn(..)),
+                _ => false,
+            }
+    }
+}
+impl<'tcx> Visitor<'tcx> for Mentions<'_, 'tcx> {
+    type NestedFilter = nested_filter::All;
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.ctx.hir()
+    }
+    fn visit_item(&mut self, item: &'tcx Item<'tcx>) {
+        if item.owner_id.def_id == self.def_id {
+            return;
+        }
+        if let ItemKind::Impl(imp) = item.kind {
+            if self.impls.iter().any(|(_, id)| *id == imp.self_ty.hir_id) {
+                let (pos, param) = ParamList::of(imp.generics).declare(&[self.lifetime.to_owned()]);
+                self.substitutes.rewrite(pos, pos, param);
+            }
+        }
+        intravisit::walk_item(self, item);
+    }
+    fn visit_ty(&mut self, ty: &'tcx Ty<'tcx>) {
+        if let Some(path) = names(ty, self.def_id) {
+            // `S::new()` and `S { .. }` are inferred
+            let qself = matches!(
+                self.ctx.parent_hir_node(ty.hir_id),
+                Node::Expr(_) | Node::Pat(_)
+            );
+            if !qself && !ty.span.from_expansion() {
+                let lifetime = if self.impls.iter().any(|(_, id)| *id == ty.hir_id) {
+                    self.lifetime
+                } else {
+                    self.in_items |= !self.elidable(ty);
+                    "'_"
+                };
+                let segment = path.segments.last().unwrap();
+                match segment.args.and_then(|args| args.args.first()) {
+                    Some(arg) => {
+                        let pos = arg.span().lo().0;
+                        self.substitutes.rewrite(pos, pos, format!("{lifetime}, "));
+                    }
+                    None => {
+                        let pos = segment.ident.span.hi().0;
+                        self.substitutes.rewrite(pos, pos, format!("<{lifetime}>"));
+                    }
+                }
+            }
+        }
+        intravisit::walk_ty(self, ty);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::{Rejection, Strategy};
+    use crate::Options;
+
+    const PATH: &str = "field_fixture.rs";
+
+    const PERSON: &str = "struct Person {\n    name: String,\n}\n\
+                          impl Person {\n    fn new(name: &str) -> Person {\n        Person { name: name.to_string() }\n    }\n    \
+                          fn len(&self) -> usize {\n        self.name.len()\n    }\n}";
+
+    fn fields() -> Options {
+        Options {
+            fields: true,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn borrowed() {
+        let rewrite = fixture::rewrite(PATH, PERSON, "--all", &fields());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "struct Person<'a> {\n    name: &'a str,\n}\n\
+             impl<'a> Person<'a> {\n    fn new(name: &'a str) -> Person<'_> {\n        Person { name }\n    }\n    \
+             fn len(&self) -> usize {\n        self.name.len()\n    }\n}"
+        );
+    }
+
+    #[test]
+    fn shorthand() {
+        // `name: &name` cannot be shortened
+        let source = "struct Person {\n    name: String,\n}\n\
+                      fn by_ref(name: &String) -> Person {\n    Person { name: name.clone() }\n}\n\
+                      fn by_value(name: String) -> usize {\n    Person { name: name.clone() }.name.len() + name.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &fields());
+        let rewritten = fixture::rewritten(&rewrite);
+        assert!(
+            rewritten.contains("fn by_ref(name: &String) -> Person<'_> {\n    Person { name }\n}")
+        );
+        assert!(rewritten.contains("Person { name: &name }.name.len()"));
+    }
+
+    #[test]
+    fn named_in_item() {
+        let source = "struct Named {\n    name: String,\n}\n\
+                      struct Holder {\n    named: Option<Named>,\n}\n\
+                      fn named(name: &str) -> Named {\n    Named { name: name.to_owned() }\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &fields());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert!(reasons.contains(&&Rejection::NamedInItem), "{reasons:?}");
+    }
+
+    #[test]
+    fn struct_update() {
+        // the borrowing rewrite would compile, but the struct is also built
+        // out of another value
+        let source = "struct Based {\n    name: String,\n    n: u8,\n}\n\
+                      impl Based {\n    fn renamed(&self) -> Based {\n        \
+                      Based { name: self.name.clone(), ..*self }\n    }\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &fields());
+        assert!(rewrite
+            .eliminations
+            .iter()
+            .all(|elim| elim.strategy != Strategy::Field));
+        assert!(rewrite.rewritten().unwrap().is_empty());
+    }
+
+    #[test]
+    fn off_by_default() {
+        let rewrite = fixture::rewrite(PATH, PERSON, "--all", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        assert!(rewrite.rewritten().unwrap().is_empty());
+    }
+}
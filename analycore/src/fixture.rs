@@ -0,0 +1,29 @@
+//! Compiled fixtures of the tests: a source rewritten as a crate of its own,
+//! with an empty `main` appended.
+
+use crate::{rewrite_crate, rewrite_fn, Input, Options, Rewrite};
+use std::path::PathBuf;
+
+const MAIN: &str = "\nfn main() {}\n";
+
+/// `source` with the clones of the function `name` eliminated, or of every
+/// function for `--all`, compiled as the file `path`.
+pub(crate) fn rewrite(path: &str, source: &str, name: &str, options: &Options) -> Rewrite {
+    let input = Input::File(PathBuf::from(path));
+    let source = format!("{source}{MAIN}");
+    let res = if name == "--all" {
+        rewrite_crate(&input, source, options)
+    } else {
+        rewrite_fn(&input, source, name, options)
+    };
+    res.unwrap()
+}
+
+/// The source after `rewrite`, which must change it, without the `main`.
+pub(crate) fn rewritten(rewrite: &Rewrite) -> String {
+    let rewritten = rewrite.rewritten().unwrap();
+    let [(_, rewritten)] = &rewritten[..] else {
+        panic!("{:?}", rewrite.rejected);
+    };
+    rewritten.strip_suffix(MAIN).unwrap().to_owned()
+}
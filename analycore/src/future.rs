@@ -0,0 +1,119 @@
+//! Clones in the bodies of `async fn`s and blocks. A borrow held across an
+//! `.await` is stored in the future, and `&T` is `Send` only if `T` is
+//! `Sync`: `let d = cell.clone(); tick().await; d.get()` has to keep its
+//! clone for the future to stay `Send`.
+
+use crate::enter;
+use crate::liveness::Liveness;
+use crate::rewrite::Rejection;
+use rustc_hir::def_id::{DefId, LocalDefId};
+use rustc_infer::infer::TyCtxtInferExt;
+use rustc_middle::mir::{Body, Local};
+use rustc_middle::ty::{Ty, TyCtxt, TypingMode};
+use rustc_span::sym;
+use rustc_trait_selection::traits;
+
+/// Whether `def_id` is the body of an `async fn` or block.
+fn is_async(ctx: &TyCtxt<'_>, def_id: LocalDefId) -> bool {
+    ctx.coroutine_is_async(def_id.to_def_id())
+}
+
+/// Whether the future of the `async` body `def_id` is `Send`.
+pub fn is_send(ctx: &TyCtxt<'_>, def_id: LocalDefId) -> bool {
+    let Some(send) = ctx.get_diagnostic_item(sym::Send) else {
+        return false;
+    };
+    let future = ctx.type_of(def_id).instantiate_identity();
+    implements(ctx, def_id, future, send)
+}
+
+/// The `async` bodies among `bodies` and the closures and coroutines in them
+/// whose future is `Send`, by def path: a rewrite must keep them so.
+pub fn send_futures(ctx: &TyCtxt<'_>, bodies: &[LocalDefId]) -> Vec<String> {
+    bodies
+        .iter()
+        .flat_map(|def_id| std::iter::once(*def_id).chain(enter::nested_bodies(ctx, *def_id)))
+        .filter(|def_id| is_async(ctx, *def_id) && is_send(ctx, *def_id))
+        .map(|def_id| ctx.def_path_str(def_id))
+        .collect()
+}
+
+/// Why the clone into `local` of the closure or coroutine `def_id` has to
+/// be kept when it is borrowed instead, if it does because of an `.await`.
+pub(crate) fn check_borrow<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+    liveness: &Liveness,
+    local: Local,
+) -> Result<(), Rejection> {
+    if !is_async(ctx, def_id) || !liveness.across_yield(body, local) {
+        return Ok(());
+    }
+    let ty = body.local_decls[local].ty;
+    if is_sync(ctx, def_id, ty) || !is_send(ctx, def_id) {
+        Ok(())
+    } else {
+        log::debug!("{ty:?} is not Sync and borrowed across an await");
+        Err(Rejection::HeldAcrossAwait)
+    }
+}
+
+/// Whether `ty` is `Sync` in the environment of `def_id`, so that a borrow
+/// of it is `Send`.
+pub(crate) fn is_sync<'tcx>(ctx: &TyCtxt<'tcx>, def_id: LocalDefId, ty: Ty<'tcx>) -> bool {
+    ctx.lang_items()
+        .sync_trait()
+        .is_some_and(|sync| implements(ctx, def_id, ty, sync))
+}
+
+/// Whether `ty` implements the trait `trait_id` in the environment of
+/// `def_id`, whatever the lifetimes.
+fn implements<'tcx>(ctx: &TyCtxt<'tcx>, def_id: LocalDefId, ty: Ty<'tcx>, trait_id: DefId) -> bool {
+    let infcx = ctx.infer_ctxt().build(TypingMode::non_body_analysis());
+    let ty = ctx.erase_regions(ty);
+    traits::type_known_to_meet_bound_modulo_regions(&infcx, ctx.param_env(def_id), ty, trait_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Rejection;
+    use crate::Options;
+
+    const PATH: &str = "future_fixture.rs";
+
+    #[test]
+    fn borrowed_across_await() {
+        let source = "async fn tick() {}\n\
+                      async fn f(s: String) -> usize {\n    let t = s.clone();\n    tick().await;\n    t.len() + s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "async fn tick() {}\n\
+             async fn f(s: String) -> usize {\n    let t = &s;\n    tick().await;\n    t.len() + s.len()\n}"
+        );
+    }
+
+    #[test]
+    fn not_sync_in_send_future() {
+        let source = "use std::cell::Cell;\n\
+                      async fn tick() {}\n\
+                      async fn f(cell: Cell<u8>) -> u8 {\n    let d = cell.clone();\n    tick().await;\n    d.get() + cell.get()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(reasons, [&Rejection::HeldAcrossAwait]);
+    }
+
+    #[test]
+    fn not_sync_in_local_future() {
+        // the future holds an `Rc` across the await, it is not `Send` anyway
+        let source = "use std::cell::Cell;\n\
+                      use std::rc::Rc;\n\
+                      async fn tick() {}\n\
+                      async fn f(cell: Cell<u8>, rc: Rc<u8>) -> u8 {\n    let d = cell.clone();\n    tick().await;\n    d.get() + cell.get() + *rc\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert!(fixture::rewritten(&rewrite).contains("let d = &cell;"));
+    }
+}
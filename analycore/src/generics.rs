@@ -0,0 +1,184 @@
+//! Lifetime parameters declared by a rewrite, spliced into a generic
+//! parameter list as written. They go first, as lifetimes must precede
+//! types and consts: `fn f<T: Clone, const N: usize>(..) where T: Copy`
+//! becomes `fn f<'a, T: Clone, const N: usize>(..) where T: Copy`, and
+//! `fn f(..)` becomes `fn f<'a>(..)`.
+
+use crate::rewrite::Range;
+use rustc_hir::{GenericParamKind, Generics, LifetimeParamKind};
+
+/// A generic parameter list in the source.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ParamList {
+    /// the list, `<` and `>` included, or the empty range where it would be
+    span: Range,
+    /// whether parameters are written in the list
+    has_params: bool,
+}
+impl ParamList {
+    /// The list of `generics`, without the parameters the compiler adds for
+    /// elided lifetimes and `impl Trait` arguments.
+    pub fn of(generics: &Generics<'_>) -> Self {
+        let has_params = generics.params.iter().any(|param| match param.kind {
+            GenericParamKind::Lifetime { kind } => matches!(kind, LifetimeParamKind::Explicit),
+            GenericParamKind::Type { synthetic, .. }
+            | GenericParamKind::Const { synthetic, .. } => !synthetic,
+        });
+        Self {
+            span: Range::from(generics.span),
+            has_params,
+        }
+    }
+    /// Where and what to insert to declare `lifetimes` before the
+    /// parameters of the list.
+    pub fn declare(&self, lifetimes: &[String]) -> (u32, String) {
+        let names = lifetimes.join(", ");
+        if self.span.lo == self.span.hi {
+            (self.span.lo, format!("<{names}>"))
+        } else if !self.has_params {
+            (self.span.lo + 1, names)
+        } else {
+            (self.span.lo + 1, format!("{names}, "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enter::{self, get_fn};
+    use crate::lifetime::{Lifetimes, Scheme};
+    use crate::rewrite::Substitutes;
+    use crate::sources::Sources;
+    use crate::CompileResult;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// `source` with `count` lifetimes declared in the generics of the
+    /// function `name`, named as the rewrite names them.
+    fn declare(source: &str, name: &str, count: usize) -> String {
+        let path = PathBuf::from("generics_fixture.rs");
+        let source = format!("{source}\nfn main() {{}}\n");
+        let sources = HashMap::from([(path.clone(), source)]);
+        let res = enter::enter(path, sources, |ctx| {
+            let def_id = get_fn(ctx, name)?;
+            let generics = ctx.hir_node_by_def_id(def_id).generics().unwrap();
+            let scheme = Scheme::default();
+            let mut lifetimes = Lifetimes::in_scope_of(ctx, &scheme, def_id);
+            let lifetimes: Vec<String> = (0..count).map(|_| lifetimes.fresh().unwrap()).collect();
+            let (pos, insert) = ParamList::of(generics).declare(&lifetimes);
+            let mut s = Substitutes::new();
+            s.rewrite(pos, pos, insert);
+            let sources = Sources::new(ctx);
+            let files = s.by_file(&sources).unwrap();
+            let [(file, edits)] = &files[..] else {
+                panic!("the edit is not in one file");
+            };
+            Ok(edits.apply(&file.source).unwrap())
+        });
+        let Ok(CompileResult::Ok(Ok(rewritten))) = res else {
+            panic!("{res:?}");
+        };
+        rewritten
+            .strip_suffix("\nfn main() {}\n")
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn no_list() {
+        assert_eq!(declare("fn f(x: &str) {}", "f", 1), "fn f<'a>(x: &str) {}");
+    }
+
+    #[test]
+    fn empty_list() {
+        // the elided lifetime of `x` is a parameter of the list to the compiler
+        assert_eq!(
+            declare("fn f<>(x: &str) {}", "f", 1),
+            "fn f<'a>(x: &str) {}"
+        );
+    }
+
+    #[test]
+    fn impl_trait() {
+        // so is the type of `x`
+        assert_eq!(
+            declare("fn f<>(x: impl Clone) {}", "f", 1),
+            "fn f<'a>(x: impl Clone) {}"
+        );
+    }
+
+    #[test]
+    fn before_types() {
+        assert_eq!(
+            declare("fn f<T: Clone>(x: T) {}", "f", 2),
+            "fn f<'a, 'b, T: Clone>(x: T) {}"
+        );
+    }
+
+    #[test]
+    fn with_lifetimes() {
+        assert_eq!(
+            declare("fn f<'x, T>(x: &'x T) {}", "f", 1),
+            "fn f<'a, 'x, T>(x: &'x T) {}"
+        );
+        assert_eq!(
+            declare("fn f<'a, T>(x: &'a T) {}", "f", 1),
+            "fn f<'b, 'a, T>(x: &'a T) {}"
+        );
+    }
+
+    #[test]
+    fn const_generics() {
+        assert_eq!(
+            declare("fn f<const N: usize, T>(x: [T; N]) {}", "f", 1),
+            "fn f<'a, const N: usize, T>(x: [T; N]) {}"
+        );
+    }
+
+    #[test]
+    fn where_clause() {
+        assert_eq!(
+            declare(
+                "fn f<T>(x: T) -> usize\nwhere\n    T: Clone,\n{\n    0\n}",
+                "f",
+                1
+            ),
+            "fn f<'a, T>(x: T) -> usize\nwhere\n    T: Clone,\n{\n    0\n}"
+        );
+        assert_eq!(
+            declare(
+                "fn f(x: u8) -> usize\nwhere\n    u8: Clone,\n{\n    0\n}",
+                "f",
+                1
+            ),
+            "fn f<'a>(x: u8) -> usize\nwhere\n    u8: Clone,\n{\n    0\n}"
+        );
+    }
+
+    #[test]
+    fn trailing_comma() {
+        assert_eq!(
+            declare("fn f<T,>(x: T) {}", "f", 1),
+            "fn f<'a, T,>(x: T) {}"
+        );
+    }
+
+    #[test]
+    fn method_of_impl_with_lifetimes() {
+        let source = "struct S<'a, 'b>(&'a str, &'b str);\n\
+                      impl<'a, 'b> S<'a, 'b> {\n    fn m<T>(&self, s: &str, t: T) {}\n}";
+        assert_eq!(
+            declare(source, "m", 1),
+            "struct S<'a, 'b>(&'a str, &'b str);\n\
+             impl<'a, 'b> S<'a, 'b> {\n    fn m<'c, T>(&self, s: &str, t: T) {}\n}"
+        );
+        let source = "struct S<'a>(&'a str);\n\
+                      impl<'a> S<'a> {\n    fn m(&self, s: &str) {}\n}";
+        assert_eq!(
+            declare(source, "m", 1),
+            "struct S<'a>(&'a str);\n\
+             impl<'a> S<'a> {\n    fn m<'b>(&self, s: &str) {}\n}"
+        );
+    }
+}
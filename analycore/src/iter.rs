@@ -0,0 +1,320 @@
+//! Clones of the elements of an iterator, `.cloned()` and
+//! `.map(|x| x.clone())`, removed when the consumer only reads them:
+//! `v.iter().cloned().any(|s| s.is_empty())` becomes
+//! `v.iter().any(|s| s.is_empty())`.
+
+use crate::borrowed;
+use crate::rewrite::{self, Elimination, Range, Rejected, Rejection, Strategy, Substitutes};
+use rustc_hir::{
+    def::Res,
+    def_id::LocalDefId,
+    intravisit::{self, Visitor},
+    Expr, ExprKind, LangItem, LoopSource, MatchSource, Node, Pat, PatKind, QPath, StmtKind,
+};
+use rustc_middle::mir::{
+    visit::PlaceContext, Body, BorrowKind, Local, Location, Operand, Rvalue, StatementKind,
+};
+use rustc_middle::ty::{TyCtxt, TypeckResults};
+use rustc_span::{sym, Span};
+
+/// Consumers taking the elements by value, which accept references as well
+/// as long as they only read them.
+const CONSUMERS: [&str; 7] = [
+    "any", "all", "position", "for_each", "count", "sum", "product",
+];
+
+/// The element clones in the body of `def_id`, of MIR `body`, removed, and
+/// the ones kept.
+pub(crate) fn eliminations<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+) -> (Vec<Elimination>, Vec<Rejected>) {
+    let mut chains = Chains {
+        ctx: *ctx,
+        body,
+        typeck: ctx.typeck(def_id),
+        eliminations: Vec::new(),
+        rejected: Vec::new(),
+    };
+    chains.visit_body(ctx.hir().body_owned_by(def_id));
+    (chains.eliminations, chains.rejected)
+}
+
+struct Chains<'a, 'tcx> {
+    ctx: TyCtxt<'tcx>,
+    body: &'a Body<'tcx>,
+    typeck: &'tcx TypeckResults<'tcx>,
+    eliminations: Vec<Elimination>,
+    rejected: Vec<Rejected>,
+}
+impl<'tcx> Chains<'_, 'tcx> {
+    /// Why the clone of the elements by `expr` has to be kept, if it does.
+    fn check_consumer(&self, expr: &'tcx Expr<'tcx>) -> Result<(), Rejection> {
+        let Node::Expr(parent) = self.ctx.parent_hir_node(expr.hir_id) else {
+            return Err(Rejection::ElementsConsumed);
+        };
+        if let Some(pat) = for_loop_pat(self.ctx, parent, expr) {
+            // `for x in v.iter().cloned() { .. }`
+            return if reads_binding(self.body, pat) {
+                Ok(())
+            } else {
+                Err(Rejection::ElementsConsumed)
+            };
+        }
+        let ExprKind::MethodCall(segment, receiver, args, _) = parent.kind else {
+            return Err(Rejection::ElementsConsumed);
+        };
+        let name = segment.ident.as_str();
+        let is_iterator = self
+            .typeck
+            .type_dependent_def_id(parent.hir_id)
+            .and_then(|method| self.ctx.trait_of_item(method))
+            .is_some_and(|trait_id| self.ctx.is_lang_item(trait_id, LangItem::Iterator));
+        if receiver.hir_id != expr.hir_id || !is_iterator || !CONSUMERS.contains(&name) {
+            return Err(Rejection::ElementsConsumed);
+        }
+        match (name, args) {
+            ("count", []) => Ok(()),
+            // implemented for references to numbers as for numbers
+            ("sum" | "product", []) => {
+                if self.typeck.expr_ty(parent).is_numeric() {
+                    Ok(())
+                } else {
+                    Err(Rejection::ElementsConsumed)
+                }
+            }
+            (_, [closure]) => {
+                let ExprKind::Closure(closure) = closure.kind else {
+                    return Err(Rejection::ElementsConsumed);
+                };
+                let params = self.ctx.hir().body(closure.body).params;
+                let body = rewrite::borrowck(&self.ctx, closure.def_id).body;
+                // the element is the last parameter
+                match params.last() {
+                    Some(param) if reads_binding(&body, param.pat) => Ok(()),
+                    _ => Err(Rejection::ElementsConsumed),
+                }
+            }
+            _ => Err(Rejection::ElementsConsumed),
+        }
+    }
+}
+impl<'tcx> Visitor<'tcx> for Chains<'_, 'tcx> {
+    fn visit_expr(&mut self, expr: &'tcx Expr<'tcx>) {
+        if clones_elements(self.ctx, self.typeck, expr) && !expr.span.from_expansion() {
+            let ExprKind::MethodCall(_, receiver, _, _) = expr.kind else {
+                unreachable!()
+            };
+            let range = Range::new(receiver.span.hi().0, expr.span.hi().0);
+            match self.check_consumer(expr) {
+                Ok(()) => {
+                    log::info!("the elements of {:?} are only read", receiver.span);
+                    let mut s = Substitutes::new();
+                    s.rewrite(range.lo, range.hi, "".to_owned());
+                    self.eliminations.push(Elimination {
+                        range,
+                        substitutes: s,
+                        params: Vec::new(),
+                        strategy: Strategy::Borrow,
+                        group: None,
+                    });
+                }
+                Err(reason) => {
+                    log::debug!("{:?} is not eliminated: {reason}", expr.span);
+                    self.rejected.push(Rejected { range, reason });
+                }
+            }
+        }
+        intravisit::walk_expr(self, expr);
+    }
+}
+
+/// Whether `expr` clones the elements of an iterator of references.
+fn clones_elements<'tcx>(ctx: TyCtxt<'tcx>, typeck: &TypeckResults<'tcx>, expr: &Expr<'_>) -> bool {
+    let ExprKind::MethodCall(_, _, args, _) = expr.kind else {
+        return false;
+    };
+    let Some(method) = typeck.type_dependent_def_id(expr.hir_id) else {
+        return false;
+    };
+    let is_map = ctx
+        .get_diagnostic_name(method)
+        .is_some_and(|name| name.as_str() == "IteratorMap");
+    match args {
+        [] => ctx.is_diagnostic_item(sym::iter_cloned, method),
+        [closure] if is_map => is_clone_closure(ctx, typeck, closure),
+        _ => false,
+    }
+}
+
+/// Whether `expr` is `|x| x.clone()` or `|x| Clone::clone(x)`.
+fn is_clone_closure<'tcx>(
+    ctx: TyCtxt<'tcx>,
+    typeck: &TypeckResults<'tcx>,
+    expr: &Expr<'_>,
+) -> bool {
+    let ExprKind::Closure(closure) = expr.kind else {
+        return false;
+    };
+    let body = ctx.hir().body(closure.body);
+    let [param] = body.params else {
+        return false;
+    };
+    let PatKind::Binding(_, id, _, None) = param.pat.kind else {
+        return false;
+    };
+    let (callee, arg) = match body.value.kind {
+        ExprKind::MethodCall(_, receiver, [], _) => {
+            (typeck.type_dependent_def_id(body.value.hir_id), receiver)
+        }
+        ExprKind::Call(callee, [arg]) => match &callee.kind {
+            ExprKind::Path(qpath) => (typeck.qpath_res(qpath, callee.hir_id).opt_def_id(), arg),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    let is_clone = callee.is_some_and(|callee| ctx.is_lang_item(callee, LangItem::CloneFn));
+    let is_param = matches!(
+        arg.kind,
+        ExprKind::Path(QPath::Resolved(None, path)) if path.res == Res::Local(id)
+    );
+    is_clone && is_param && typeck.node_type(param.hir_id).is_ref()
+}
+
+/// Whether the closure `def_id` is the `|x| x.clone()` of a `.map` cloning
+/// the elements of an iterator, eliminated along with the `.map`.
+pub(crate) fn is_element_clone(ctx: &TyCtxt<'_>, def_id: LocalDefId) -> bool {
+    let Node::Expr(closure) = ctx.hir_node_by_def_id(def_id) else {
+        return false;
+    };
+    let Node::Expr(map) = ctx.parent_hir_node(closure.hir_id) else {
+        return false;
+    };
+    clones_elements(*ctx, ctx.typeck(def_id), map)
+}
+
+/// The pattern of the `for` loop over `head`, given its parent `call`: a
+/// `for` loop is lowered to
+/// `match IntoIterator::into_iter(head) { mut iter => loop { match
+/// Iterator::next(&mut iter) { None => break, Some(pat) => body } } }`.
+fn for_loop_pat<'tcx>(
+    ctx: TyCtxt<'tcx>,
+    call: &'tcx Expr<'tcx>,
+    head: &Expr<'_>,
+) -> Option<&'tcx Pat<'tcx>> {
+    let ExprKind::Call(func, [arg]) = call.kind else {
+        return None;
+    };
+    let is_into_iter = matches!(
+        func.kind,
+        ExprKind::Path(QPath::LangItem(LangItem::IntoIterIntoIter, _))
+    );
+    if !is_into_iter || arg.hir_id != head.hir_id {
+        return None;
+    }
+    let Node::Expr(outer) = ctx.parent_hir_node(call.hir_id) else {
+        return None;
+    };
+    let ExprKind::Match(_, [iter_arm], MatchSource::ForLoopDesugar) = outer.kind else {
+        return None;
+    };
+    let ExprKind::Loop(block, _, LoopSource::ForLoop, _) = iter_arm.body.kind else {
+        return None;
+    };
+    let [stmt] = block.stmts else {
+        return None;
+    };
+    let StmtKind::Expr(next) = stmt.kind else {
+        return None;
+    };
+    let ExprKind::Match(_, [_, some_arm], MatchSource::ForLoopDesugar) = next.kind else {
+        return None;
+    };
+    match some_arm.pat.kind {
+        PatKind::Struct(_, [field], _) => Some(field.pat),
+        _ => None,
+    }
+}
+
+/// Whether the variable bound by `pat` is only borrowed, or has its fields
+/// copied, in `body`, so that it can be a reference as well.
+fn reads_binding(body: &Body<'_>, pat: &Pat<'_>) -> bool {
+    let PatKind::Binding(_, _, _, None) = pat.kind else {
+        return false;
+    };
+    let Some(local) = binding(body, pat.span) else {
+        return false;
+    };
+    borrowed::uses(body, local)
+        .into_iter()
+        .all(|(context, location)| reads(body, context, location))
+}
+
+/// The user variable of `body` bound at `span`.
+fn binding(body: &Body<'_>, span: Span) -> Option<Local> {
+    body.local_decls
+        .iter_enumerated()
+        .find(|(_, decl)| decl.is_user_variable() && decl.source_info.span == span)
+        .map(|(local, _)| local)
+}
+
+/// Whether the use of a variable at `location` only reads it.
+fn reads(body: &Body<'_>, context: PlaceContext, location: Location) -> bool {
+    if context.is_drop() || context.is_place_assignment() {
+        return true;
+    }
+    match borrowed::statement(body, location) {
+        Some(StatementKind::Assign(assign)) => match &assign.1 {
+            Rvalue::Ref(_, BorrowKind::Shared, _) | Rvalue::Discriminant(_) => true,
+            Rvalue::Use(Operand::Copy(place)) => !place.projection.is_empty(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Rejection;
+    use crate::Options;
+
+    const PATH: &str = "iter_fixture.rs";
+
+    #[test]
+    fn read_by_consumers() {
+        let source =
+            "fn any(v: &Vec<String>) -> bool {\n    v.iter().cloned().any(|s| s.is_empty())\n}\n\
+                      fn total(v: &[u64]) -> u64 {\n    v.iter().map(|x| x.clone()).sum()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn any(v: &Vec<String>) -> bool {\n    v.iter().any(|s| s.is_empty())\n}\n\
+             fn total(v: &[u64]) -> u64 {\n    v.iter().sum()\n}"
+        );
+    }
+
+    #[test]
+    fn read_by_for_loop() {
+        let source = "fn each(v: &[String]) {\n    for s in v.iter().cloned() {\n        println!(\"{s}\");\n    }\n}";
+        let rewrite = fixture::rewrite(PATH, source, "each", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "fn each(v: &[String]) {\n    for s in v.iter() {\n        println!(\"{s}\");\n    }\n}"
+        );
+    }
+
+    #[test]
+    fn elements_consumed() {
+        let source = "fn collected(v: &[String]) -> Vec<String> {\n    v.iter().cloned().collect()\n}\n\
+                      fn dropped(v: &[String]) {\n    for s in v.iter().cloned() {\n        drop(s);\n    }\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(
+            reasons,
+            [&Rejection::ElementsConsumed, &Rejection::ElementsConsumed]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! rustc-style JSON diagnostics (`--error-format=json`), so that the
+//! eliminations can be applied by `rustfix` like the suggestions of `cargo fix`.
+
+use crate::rewrite::{EditError, Elimination, Range, Strategy};
+use crate::sources::Sources;
+
+/// Renders `elim`, found in `sources`, as a warning on the removed clone,
+/// with one `MachineApplicable` suggestion per edit.
+pub fn diagnostic(sources: &Sources, elim: &Elimination) -> Result<String, EditError> {
+    let (file, range) = sources
+        .local(elim.range)
+        .ok_or(EditError::OutsideSources(elim.range.lo))?;
+    let path = file.path.to_string_lossy();
+    let primary = span(&path, &file.source, range, None);
+    let (line, column) = line_column(&file.source, range.lo as usize);
+    let mut suggestions = Vec::new();
+    for (file, s) in elim.substitutes.by_file(sources)? {
+        let path = file.path.to_string_lossy();
+        suggestions.extend(
+            s.validate(&file.source)?
+                .into_iter()
+                .map(|(lo, hi, insert)| {
+                    span(&path, &file.source, Range::new(lo, hi), Some(&insert))
+                }),
+        );
+    }
+    let message = "redundant clone";
+    let help = match elim.strategy {
+        Strategy::Borrow => "remove the clone and borrow the value instead",
+        Strategy::Move => "remove the clone and move the value instead",
+        Strategy::Copy => "remove the clone and copy the value instead",
+        Strategy::Field => "borrow the cloned values in the fields instead",
+    };
+    Ok(format!(
+        concat!(
+            r#"{{"$message_type":"diagnostic","message":{},"code":null,"level":"warning","#,
+            r#""spans":[{}],"children":[{{"message":{},"code":null,"level":"help","#,
+            r#""spans":[{}],"children":[],"rendered":null}}],"rendered":{}}}"#
+        ),
+        string(message),
+        primary,
+        string(help),
+        suggestions.join(","),
+        string(&format!(
+            "warning: {message}\n --> {path}:{}:{}\n  = help: {help}\n",
+            line + 1,
+            column + 1
+        )),
+    ))
+}
+
+fn span(path: &str, source: &str, range: Range, replacement: Option<&str>) -> String {
+    let (lo, hi) = (range.lo as usize, range.hi as usize);
+    let (line_start, column_start) = line_column(source, lo);
+    let (line_end, column_end) = line_column(source, hi);
+    let text: Vec<String> = source
+        .split('\n')
+        .enumerate()
+        .skip(line_start)
+        .take(line_end - line_start + 1)
+        .map(|(line, text)| {
+            let start = if line == line_start { column_start } else { 0 };
+            let end = if line == line_end {
+                column_end
+            } else {
+                text.chars().count()
+            };
+            format!(
+                r#"{{"text":{},"highlight_start":{},"highlight_end":{}}}"#,
+                string(text),
+                start + 1,
+                end + 1
+            )
+        })
+        .collect();
+    let (replacement, applicability) = match replacement {
+        Some(replacement) => (string(replacement), string("MachineApplicable")),
+        None => ("null".to_owned(), "null".to_owned()),
+    };
+    format!(
+        concat!(
+            r#"{{"file_name":{},"byte_start":{},"byte_end":{},"line_start":{},"line_end":{},"#,
+            r#""column_start":{},"column_end":{},"is_primary":true,"text":[{}],"label":null,"#,
+            r#""suggested_replacement":{},"suggestion_applicability":{},"expansion":null}}"#
+        ),
+        string(path),
+        lo,
+        hi,
+        line_start + 1,
+        line_end + 1,
+        column_start + 1,
+        column_end + 1,
+        text.join(","),
+        replacement,
+        applicability,
+    )
+}
+
+/// 0-based line and column (in chars) of the byte `offset`.
+pub(crate) fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    // the compiler does not count a BOM
+    let head = source[..offset].trim_start_matches('\u{feff}');
+    let line_start = head.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (
+        head.matches('\n').count(),
+        head[line_start..].chars().count(),
+    )
+}
+
+/// JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::Options;
+    use serde_json::Value;
+
+    #[test]
+    fn crlf_and_bom() {
+        let source = "\u{feff}fn f(s: String) -> usize {\r\n    let t = s.clone();\r\n    t.len() + s.len()\r\n}\r\n";
+        let rewrite = fixture::rewrite("json_fixture.rs", source, "f", &Options::default());
+        let [elim] = &rewrite.eliminations[..] else {
+            panic!("{:?}", rewrite.rejected);
+        };
+        let diagnostic: Value =
+            serde_json::from_str(&diagnostic(&rewrite.sources, elim).unwrap()).unwrap();
+        assert_eq!(diagnostic["level"], "warning");
+        let primary = &diagnostic["spans"][0];
+        let clone = source.find(".clone()").unwrap();
+        assert_eq!(primary["byte_start"], clone);
+        assert_eq!(primary["byte_end"], clone + ".clone()".len());
+        assert_eq!(primary["line_start"], 2);
+        assert_eq!(primary["column_start"], 14);
+        assert_eq!(primary["suggested_replacement"], Value::Null);
+        // the edits, in the order of the file
+        let suggestions = diagnostic["children"][0]["spans"].as_array().unwrap();
+        let edits: Vec<(u64, u64, u64, &str, &str)> = suggestions
+            .iter()
+            .map(|span| {
+                (
+                    span["byte_start"].as_u64().unwrap(),
+                    span["byte_end"].as_u64().unwrap(),
+                    span["line_start"].as_u64().unwrap(),
+                    span["suggested_replacement"].as_str().unwrap(),
+                    span["suggestion_applicability"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        let string = source.find("String").unwrap() as u64;
+        let clone = clone as u64;
+        assert_eq!(
+            edits,
+            [
+                (string, string + 6, 1, "&str", "MachineApplicable"),
+                (clone, clone + 8, 2, "", "MachineApplicable"),
+            ]
+        );
+    }
+}
@@ -1,73 +1,573 @@
 #![feature(rustc_private)]
 
+pub mod borrowed;
+pub mod call_site;
+pub mod check;
+pub mod closure;
+pub mod conversion;
+pub mod copy;
+pub mod diff;
 pub mod enter;
+pub mod field;
+#[cfg(test)]
+mod fixture;
+pub mod future;
+pub mod generics;
+pub mod iter;
+pub mod json;
+pub mod lifetime;
+pub mod liveness;
+pub mod report;
 pub mod rewrite;
+pub mod sink;
+pub mod sources;
 
 pub extern crate polonius_engine;
 pub extern crate rustc_borrowck;
+pub extern crate rustc_data_structures;
 pub extern crate rustc_driver;
 pub extern crate rustc_errors;
 pub extern crate rustc_hash;
 pub extern crate rustc_hir;
+pub extern crate rustc_index;
+pub extern crate rustc_infer;
 pub extern crate rustc_interface;
 pub extern crate rustc_middle;
+pub extern crate rustc_mir_dataflow;
 pub extern crate rustc_session;
 pub extern crate rustc_span;
+pub extern crate rustc_trait_selection;
 
+use conversion::Conversions;
+use lifetime::Scheme;
+use rewrite::{EditError, Elimination, Range, Rejected, Rejection, Substitutes};
+use rustc_hir::def_id::LocalDefId;
+use rustc_middle::ty::TyCtxt;
+use sources::{SourceFile, Sources};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::panic;
 use std::path::PathBuf;
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Error {
     ArgIndexOut,
     FnNotFound,
+    /// the function name matches all of these def paths
+    AmbiguousFn(Vec<String>),
+    /// the compiler reported these errors
+    Compile(Vec<String>),
     Internal,
 }
 
+/// How the compiler is invoked for the crate to rewrite.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Input {
+    /// a single source file compiled with the default options
+    File(PathBuf),
+    /// full rustc command line, e.g. as received by a `RUSTC_WRAPPER`
+    Args(Vec<String>),
+}
+impl Input {
+    /// Path to the crate root.
+    pub fn root(&self) -> Option<PathBuf> {
+        match self {
+            Input::File(path) => Some(path.clone()),
+            Input::Args(args) => enter::crate_root(args),
+        }
+    }
+    fn enter<T: Send>(
+        &self,
+        sources: HashMap<PathBuf, String>,
+        f: impl for<'tcx> FnOnce(&TyCtxt<'tcx>) -> Result<T, Error> + Send + panic::UnwindSafe,
+    ) -> Result<CompileResult<Result<T, Error>>, Error> {
+        match self {
+            Input::File(path) => enter::enter(path.clone(), sources, f),
+            Input::Args(args) => enter::enter_with_args(args, sources, f),
+        }
+    }
+}
+
+/// Verified clone eliminations in the source files of a crate.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    /// contents before the rewrite
+    pub sources: Sources,
+    pub eliminations: Vec<Elimination>,
+    /// clones found but kept
+    pub rejected: Vec<Rejected>,
+}
+impl Rewrite {
+    /// All the edits of the eliminations.
+    pub fn substitutes(&self) -> Substitutes {
+        substitutes(&self.eliminations)
+    }
+    /// Contents after the rewrite of the files changed.
+    pub fn rewritten(&self) -> Result<Vec<(&SourceFile, String)>, EditError> {
+        rewritten(&self.sources, &self.eliminations)
+    }
+    /// Unified diff from the original to the rewritten contents.
+    pub fn diff(&self) -> Result<String, EditError> {
+        let mut diff = String::new();
+        for (file, s) in self.substitutes().by_file(&self.sources)? {
+            diff.push_str(&diff::unified(
+                &file.path.to_string_lossy(),
+                &file.source,
+                &s,
+            )?);
+        }
+        Ok(diff)
+    }
+    /// Writes the rewrite to `out` as `output`, or to the files for [`Output::InPlace`].
+    pub fn write(&self, output: Output, out: &mut impl io::Write) -> io::Result<()> {
+        let invalid = |e: EditError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        match output {
+            Output::Source => {
+                let rewritten = self.rewritten().map_err(invalid)?;
+                for (file, source) in &rewritten {
+                    if rewritten.len() > 1 {
+                        writeln!(out, "// {}", file.path.to_string_lossy())?;
+                    }
+                    writeln!(out, "{source}")?;
+                }
+            }
+            Output::Diff => {
+                write!(out, "{}", self.diff().map_err(invalid)?)?;
+            }
+            Output::Json => {
+                for elim in &self.eliminations {
+                    let diagnostic = json::diagnostic(&self.sources, elim).map_err(invalid)?;
+                    writeln!(out, "{diagnostic}")?;
+                }
+            }
+            Output::InPlace => {
+                for (file, source) in self.rewritten().map_err(invalid)? {
+                    fs::write(&file.path, source)?;
+                }
+            }
+            Output::Report => {
+                write!(out, "{}", report::human(self))?;
+            }
+            Output::ReportJson => {
+                write!(out, "{}", report::json(self))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a verified rewrite is emitted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    /// print the whole rewritten source
+    Source,
+    /// print a unified diff
+    Diff,
+    /// print a rustc-style JSON diagnostic per elimination, for `rustfix`
+    Json,
+    /// write the rewritten source back to the file
+    InPlace,
+    /// print whether and why each clone was eliminated or kept
+    Report,
+    /// [`Output::Report`] as one JSON object per clone
+    ReportJson,
+}
+impl Output {
+    /// Whether the output describes the clones rather than the rewritten source.
+    pub fn is_report(&self) -> bool {
+        matches!(self, Output::Report | Output::ReportJson)
+    }
+    pub fn from_flag(flag: &str) -> Option<Self> {
+        match flag {
+            "--diff" => Some(Output::Diff),
+            "--json" => Some(Output::Json),
+            "--in-place" => Some(Output::InPlace),
+            "--report" => Some(Output::Report),
+            "--report-json" => Some(Output::ReportJson),
+            _ => None,
+        }
+    }
+}
+
+/// Tuning of the rewrite.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Options {
+    /// calls eliminated like clones
+    pub conversions: Conversions,
+    /// also turn the owned fields always initialized by clones into borrows,
+    /// adding a lifetime parameter to their struct (`--fields`)
+    pub fields: bool,
+    /// names of the lifetime parameters added (`--lifetimes=<letters|name>`)
+    pub lifetimes: Scheme,
+}
+impl Options {
+    /// Sets the option of a command line `flag`, `false` if there is none.
+    pub fn set_flag(&mut self, flag: &str) -> bool {
+        if let Some(name) = flag.strip_prefix("--lifetimes=") {
+            let Some(scheme) = Scheme::from_name(name) else {
+                return false;
+            };
+            self.lifetimes = scheme;
+            return true;
+        }
+        match flag {
+            "--fields" => self.fields = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum CompileResult<T> {
     Ok(T),
     Err(T),
 }
 
-pub fn rewrite_fn(name: PathBuf, source: String, fn_name: &str) -> Result<Option<String>, Error> {
-    let do_rewrite = |source: String| {
-        enter::enter(name.clone(), source.clone(), |ctx| {
-            if let Some(item) = enter::get_fn(ctx, fn_name) {
-                let (sig, gen, bid) = item.expect_fn();
-                return Ok(
-                    rewrite::rewrite(source, ctx, *sig, *gen, bid.hir_id.owner.def_id)
-                        .map_err(|_| Error::Internal)?,
-                );
+/// Rewrites `fn_name` in the crate of `input`, whose root is `source`.
+pub fn rewrite_fn(
+    input: &Input,
+    source: String,
+    fn_name: &str,
+    options: &Options,
+) -> Result<Rewrite, Error> {
+    rewrite_bodies(input, source, options, |ctx| {
+        Ok(vec![enter::get_fn(ctx, fn_name)?])
+    })
+}
+
+/// Rewrites every function in the crate of `input`, whose root is `source`,
+/// in one run.
+pub fn rewrite_crate(input: &Input, source: String, options: &Options) -> Result<Rewrite, Error> {
+    rewrite_bodies(input, source, options, |ctx| {
+        Ok(enter::fn_bodies(ctx).collect())
+    })
+}
+
+/// Eliminates the clones of all the `bodies`, then verifies the combined
+/// rewrite once.
+fn rewrite_bodies(
+    input: &Input,
+    source: String,
+    options: &Options,
+    bodies: impl for<'tcx> Fn(&TyCtxt<'tcx>) -> Result<Vec<LocalDefId>, Error>
+        + Sync
+        + panic::RefUnwindSafe,
+) -> Result<Rewrite, Error> {
+    let root = input.root().ok_or(Error::Internal)?;
+    let files = HashMap::from([(root, source)]);
+    let do_rewrite = |files: HashMap<PathBuf, String>| {
+        input.enter(files, |ctx| {
+            let mut eliminations = Vec::new();
+            let mut rejected = Vec::new();
+            let bodies = bodies(ctx)?;
+            let send = future::send_futures(ctx, &bodies);
+            for def_id in bodies {
+                if !enter::in_source_file(ctx, ctx.def_span(def_id)) {
+                    log::debug!("{def_id:?} is not in a source file; skipped");
+                    continue;
+                }
+                let Some(gen) = ctx.hir_node_by_def_id(def_id).generics() else {
+                    return Err(Error::Internal);
+                };
+                // closures and coroutines are bodies of their own
+                for body in std::iter::once(def_id).chain(enter::nested_bodies(ctx, def_id)) {
+                    let (elims, rejs) = rewrite::eliminations(
+                        ctx,
+                        gen,
+                        body,
+                        &options.conversions,
+                        &options.lifetimes,
+                    );
+                    eliminations.extend(elims);
+                    rejected.extend(rejs);
+                }
             }
-            Err(Error::FnNotFound)
+            if options.fields {
+                let (fields, kept, claimed) =
+                    field::eliminations(ctx, &options.conversions, &options.lifetimes);
+                // the clones initializing the fields are eliminated with them
+                let unclaimed =
+                    |range: &Range| !claimed.iter().any(|c| c.lo <= range.lo && range.hi <= c.hi);
+                eliminations.retain(|elim: &Elimination| unclaimed(&elim.range));
+                rejected.retain(|rej: &Rejected| unclaimed(&rej.range));
+                eliminations.extend(fields);
+                rejected.extend(kept);
+            }
+            Ok((Sources::new(ctx), eliminations, rejected, send))
         })
     };
-    let do_check = |source: String| {
+    let do_check = |files: HashMap<PathBuf, String>, send: &[String]| {
         log::info!("type & borrow check");
-        enter::enter(name.clone(), source, |ctx| {
-            if let Some(item) = enter::get_fn(ctx, fn_name) {
-                let (_sig, _gen, bid) = item.expect_fn();
-                let bck = rewrite::borrowck(ctx, bid.hir_id.owner.def_id);
-                return Ok(true);
+        input.enter(files, |ctx| {
+            if options.fields {
+                check::check_crate(ctx);
+            } else {
+                check::check(ctx, &bodies(ctx)?, send);
             }
-            Ok(false)
+            Ok(())
         })
     };
-    let mut updated = source;
-    let upd = do_rewrite(updated.clone());
+    let upd = do_rewrite(files.clone());
     log::info!("rewrite exited");
-    match upd {
-        Ok(CompileResult::Ok(Ok(Some(upd)))) => {
-            updated = upd;
-        }
-        Err(e) => {
+    let (sources, eliminations, rejected, send) = match upd {
+        Ok(CompileResult::Ok(Ok(res))) => res,
+        // the errors of the compiler are returned as `Error::Compile`
+        Ok(CompileResult::Ok(Err(e)) | CompileResult::Err(Err(e))) | Err(e) => {
             return Err(e);
         }
-        _ => {}
+        // failed without an error to report
+        Ok(CompileResult::Err(Ok(_))) => {
+            log::error!("the compilation of the crate failed");
+            return Err(Error::Internal);
+        }
+    };
+    let mut rewrite = Rewrite {
+        sources,
+        eliminations: Vec::new(),
+        rejected,
+    };
+    let check = |eliminations: &[Elimination]| {
+        let mut contents = files.clone();
+        match rewritten(&rewrite.sources, eliminations) {
+            Ok(changed) => {
+                contents.extend(changed.into_iter().map(|(file, s)| (file.path.clone(), s)));
+            }
+            // not worth compiling
+            Err(e) => {
+                log::info!("{e}");
+                return Err(Rejection::EditConflict);
+            }
+        }
+        match do_check(contents, &send) {
+            Ok(CompileResult::Ok(Ok(()))) => Ok(()),
+            Err(Error::Compile(diagnostics)) => Err(Rejection::VerificationFailed(diagnostics)),
+            res => Err(Rejection::VerificationFailed(vec![format!("{res:?}")])),
+        }
+    };
+    let mut accepted = Vec::new();
+    let mut failed = Vec::new();
+    bisect(&mut accepted, groups(eliminations), &mut failed, &check);
+    rewrite.rejected.extend(failed);
+    rewrite.eliminations = merge_covered(accepted, &mut rewrite.rejected);
+    Ok(rewrite)
+}
+
+/// The `eliminations` without the ones of the clones another one removes,
+/// as it passes borrows to its callers: `f(x.clone())` becomes `f(&x)` with
+/// the parameters of `f`. Their edits are merged into that one, and the
+/// `rejected` entries of the clones removed are dropped.
+fn merge_covered(eliminations: Vec<Elimination>, rejected: &mut Vec<Rejected>) -> Vec<Elimination> {
+    rejected.retain(|rej| {
+        !eliminations
+            .iter()
+            .any(|elim| elim.substitutes.covers(rej.range))
+    });
+    let covered: Vec<bool> = eliminations
+        .iter()
+        .map(|elim| {
+            eliminations
+                .iter()
+                .any(|other| other.range != elim.range && other.substitutes.covers(elim.range))
+        })
+        .collect();
+    let (covered, kept): (Vec<_>, Vec<_>) = eliminations
+        .into_iter()
+        .zip(covered)
+        .partition(|(_, covered)| *covered);
+    let mut kept: Vec<Elimination> = kept.into_iter().map(|(elim, _)| elim).collect();
+    for (elim, _) in covered {
+        match kept
+            .iter_mut()
+            .find(|other| other.substitutes.covers(elim.range))
+        {
+            Some(other) => {
+                log::debug!("{:?} is removed along with {:?}", elim.range, other.range);
+                other.substitutes.merge(&elim.substitutes);
+            }
+            // covered only by covered ones
+            None => kept.push(elim),
+        }
+    }
+    kept
+}
+
+/// The `eliminations` in groups verified as one: the ones of a `group`
+/// together, in the order of the first of each.
+fn groups(eliminations: Vec<Elimination>) -> Vec<Vec<Elimination>> {
+    let mut groups: Vec<Vec<Elimination>> = Vec::new();
+    for elim in eliminations {
+        let group = elim
+            .group
+            .and_then(|key| groups.iter_mut().find(|group| group[0].group == Some(key)));
+        match group {
+            Some(group) => group.push(elim),
+            None => groups.push(vec![elim]),
+        }
+    }
+    groups
+}
+
+/// Adds to `accepted` as many of the `candidates` groups as possible while
+/// the result still passes `check`. When the groups fail together, each half
+/// is retried on its own, so one bad elimination does not discard the others;
+/// the eliminations of a group failing alone are rejected for the reason
+/// `check` gives.
+fn bisect(
+    accepted: &mut Vec<Elimination>,
+    candidates: Vec<Vec<Elimination>>,
+    rejected: &mut Vec<Rejected>,
+    check: &impl Fn(&[Elimination]) -> Result<(), Rejection>,
+) {
+    if candidates.is_empty() {
+        return;
     }
-    if do_check(updated.clone()) == Ok(CompileResult::Ok(Ok(true))) {
-        Ok(Some(updated))
+    let mut trial = accepted.clone();
+    trial.extend(candidates.iter().flatten().cloned());
+    let res = check(&trial);
+    if res.is_ok() {
+        *accepted = trial;
+    } else if let (Err(reason), [group]) = (res, &candidates[..]) {
+        for elim in group {
+            log::info!("{:?} fails verification: {reason}", elim.range);
+            rejected.push(Rejected {
+                range: elim.range,
+                reason: reason.clone(),
+            });
+        }
     } else {
-        Ok(None)
+        let mut first = candidates;
+        let second = first.split_off(first.len() / 2);
+        bisect(accepted, first, rejected, check);
+        bisect(accepted, second, rejected, check);
+    }
+}
+
+/// The contents after `eliminations` of the files of `sources` they change.
+fn rewritten<'s>(
+    sources: &'s Sources,
+    eliminations: &[Elimination],
+) -> Result<Vec<(&'s SourceFile, String)>, EditError> {
+    substitutes(eliminations)
+        .by_file(sources)?
+        .into_iter()
+        .map(|(file, s)| Ok((file, s.apply(&file.source)?)))
+        .collect()
+}
+
+/// All the edits of `eliminations`.
+fn substitutes(eliminations: &[Elimination]) -> Substitutes {
+    let mut s = Substitutes::new();
+    for elim in eliminations {
+        s.merge(&elim.substitutes);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rewrite::Strategy;
+
+    #[test]
+    fn compile_error() {
+        let source = "fn f(s: String) -> usize {\n    s.clone().len() + undefined\n}\n";
+        let input = Input::File(PathBuf::from("lib_fixture.rs"));
+        let res = rewrite_fn(&input, source.to_owned(), "f", &Options::default());
+        let Err(Error::Compile(diagnostics)) = res else {
+            panic!("{res:?}");
+        };
+        assert!(diagnostics[0].contains("cannot find value `undefined`"));
+    }
+
+    fn elimination(range: (u32, u32), replaces: &[(u32, u32)]) -> Elimination {
+        let mut substitutes = Substitutes::new();
+        for (from, until) in replaces {
+            substitutes.rewrite(*from, *until, "".to_owned());
+        }
+        Elimination {
+            range: Range::new(range.0, range.1),
+            substitutes,
+            params: Vec::new(),
+            strategy: Strategy::Borrow,
+            group: None,
+        }
+    }
+
+    #[test]
+    fn merged_when_covered() {
+        // `c` is in the call rewritten by `b`, itself in the one of `a`
+        let a = elimination((0, 2), &[(0, 30)]);
+        let b = elimination((10, 12), &[(10, 20)]);
+        let c = elimination((14, 16), &[(14, 16), (40, 44)]);
+        let d = elimination((50, 52), &[(50, 52)]);
+        let rejected = |lo, hi| Rejected {
+            range: Range::new(lo, hi),
+            reason: Rejection::CloneConsumed,
+        };
+        let mut kept = vec![rejected(22, 24), rejected(60, 62)];
+        let merged = merge_covered(vec![c, b, a, d], &mut kept);
+        let ranges: Vec<Range> = merged.iter().map(|elim| elim.range).collect();
+        assert_eq!(ranges, [Range::new(0, 2), Range::new(50, 52)]);
+        // with the edits of the clones it removes
+        assert!(merged[0].substitutes.covers(Range::new(40, 44)));
+        let kept: Vec<Range> = kept.iter().map(|rej| rej.range).collect();
+        assert_eq!(kept, [Range::new(60, 62)]);
+    }
+
+    #[test]
+    fn failing_group_dropped() {
+        // the clones of `g` retype `s` together, and `let w: String = u` does
+        // not compile with `u` borrowed
+        let source =
+            "fn g(s: String) -> usize {\n    let t = s.clone();\n    let u = s.clone();\n    \
+                      let w: String = u;\n    t.len() + w.len() + s.len()\n}\n\
+                      fn f(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}";
+        let rewrite = fixture::rewrite("lib_fixture.rs", source, "--all", &Options::default());
+        let lines = |ranges: Vec<Range>| -> Vec<usize> {
+            ranges
+                .into_iter()
+                .map(|range| {
+                    let (file, range) = rewrite.sources.local(range).unwrap();
+                    json::line_column(&file.source, range.lo as usize).0 + 1
+                })
+                .collect()
+        };
+        assert_eq!(
+            lines(rewrite.eliminations.iter().map(|elim| elim.range).collect()),
+            [8]
+        );
+        assert_eq!(
+            lines(rewrite.rejected.iter().map(|rej| rej.range).collect()),
+            [2, 3]
+        );
+        assert!(rewrite
+            .rejected
+            .iter()
+            .all(|rej| matches!(rej.reason, Rejection::VerificationFailed(_))));
+    }
+
+    #[test]
+    fn written() {
+        let source = "fn f(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}";
+        let rewrite = fixture::rewrite("lib_fixture.rs", source, "f", &Options::default());
+        let written = |output| {
+            let mut out = Vec::new();
+            rewrite.write(output, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            written(Output::Diff),
+            "--- a/lib_fixture.rs\n+++ b/lib_fixture.rs\n@@ -1,5 +1,5 @@\n\
+             -fn f(s: String) -> usize {\n\
+             -    let t = s.clone();\n\
+             +fn f(s: &str) -> usize {\n\
+             +    let t = s;\n     \
+             t.len() + s.len()\n }\n fn main() {}\n"
+        );
+        let json = written(Output::Json);
+        assert_eq!(json.lines().count(), 1);
+        assert!(json.starts_with(r#"{"$message_type":"diagnostic","message":"redundant clone""#));
     }
 }
@@ -0,0 +1,201 @@
+//! Names of the lifetime parameters a rewrite declares. A name must not be
+//! declared already where it is: `fn f<'a>(x: &'a str, y: &String)` has
+//! `y` retyped to `&'b str`, and a method of `impl<'a> S<'a>` does not take
+//! `'a` either.
+
+use rustc_hir::{
+    def_id::LocalDefId,
+    intravisit::{self, Visitor},
+    GenericParam, GenericParamKind, ItemKind, OwnerNode,
+};
+use rustc_middle::ty::TyCtxt;
+use rustc_span::symbol::kw;
+use std::collections::HashSet;
+
+/// How the lifetimes declared by a rewrite are named.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub enum Scheme {
+    /// `'a` to `'z`
+    #[default]
+    Letters,
+    /// `'name`, then `'name1`, `'name2` and on
+    Named(String),
+}
+impl Scheme {
+    /// The scheme of `--lifetimes=<name>`: `letters`, or else the name of a
+    /// lifetime such as `src`, `None` if it is not one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut chars = name.chars();
+        let is_ident = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        match name {
+            "letters" => Some(Scheme::Letters),
+            _ if !is_ident || name == "_" || name == "static" => None,
+            _ => Some(Scheme::Named(name.to_owned())),
+        }
+    }
+    /// The `i`th name of the scheme, if it has that many.
+    fn name(&self, i: u32) -> Option<String> {
+        match self {
+            Scheme::Letters => (i < 26).then(|| format!("'{}", char::from(b'a' + i as u8))),
+            Scheme::Named(name) if i == 0 => Some(format!("'{name}")),
+            Scheme::Named(name) => Some(format!("'{name}{i}")),
+        }
+    }
+}
+
+/// The names of the scheme are all declared already.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Exhausted;
+
+/// Allocates lifetime names of a scheme, skipping the declared ones.
+pub struct Lifetimes<'a> {
+    scheme: &'a Scheme,
+    taken: HashSet<String>,
+    next: u32,
+}
+impl<'a> Lifetimes<'a> {
+    /// Names fresh in `taken`.
+    pub fn new(scheme: &'a Scheme, taken: HashSet<String>) -> Self {
+        Self {
+            scheme,
+            taken,
+            next: 0,
+        }
+    }
+    /// Names fresh for the generics of the function `def_id`, or of the
+    /// function the closure `def_id` is in: no item it is in declares them.
+    pub fn in_scope_of(ctx: &TyCtxt<'_>, scheme: &'a Scheme, def_id: LocalDefId) -> Self {
+        let hir_id = ctx.local_def_id_to_hir_id(def_id);
+        let owners = std::iter::once(hir_id.owner)
+            .chain(ctx.hir().parent_owner_iter(hir_id).map(|(owner, _)| owner));
+        Self::new(
+            scheme,
+            declared(owners.map(|owner| ctx.hir_owner_node(owner))),
+        )
+    }
+    /// The next name of the scheme declared nowhere.
+    pub fn fresh(&mut self) -> Result<String, Exhausted> {
+        loop {
+            let name = self.scheme.name(self.next).ok_or(Exhausted)?;
+            self.next = self.next.checked_add(1).ok_or(Exhausted)?;
+            if self.taken.insert(name.clone()) {
+                return Ok(name);
+            }
+        }
+    }
+}
+
+/// The lifetimes declared by the generics of `nodes` and by the binders in
+/// their signatures, `for<'a>`; nested items and bodies aside, as are the
+/// items of modules.
+pub(crate) fn declared<'tcx>(nodes: impl IntoIterator<Item = OwnerNode<'tcx>>) -> HashSet<String> {
+    let mut declared = Declared(HashSet::new());
+    for node in nodes {
+        match node {
+            OwnerNode::Item(item) if !matches!(item.kind, ItemKind::Mod(_)) => {
+                declared.visit_item(item)
+            }
+            OwnerNode::ImplItem(item) => declared.visit_impl_item(item),
+            OwnerNode::TraitItem(item) => declared.visit_trait_item(item),
+            OwnerNode::ForeignItem(item) => declared.visit_foreign_item(item),
+            _ => {}
+        }
+    }
+    declared.0
+}
+
+struct Declared(HashSet<String>);
+impl<'tcx> Visitor<'tcx> for Declared {
+    fn visit_generic_param(&mut self, param: &'tcx GenericParam<'tcx>) {
+        if let GenericParamKind::Lifetime { .. } = param.kind {
+            let name = param.name.ident().name;
+            if name != kw::UnderscoreLifetime {
+                self.0.insert(name.to_string());
+            }
+        }
+        intravisit::walk_generic_param(self, param);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::Options;
+
+    /// `source` with the clones of the function `name` eliminated.
+    fn rewrite(source: &str, name: &str) -> String {
+        let rewrite = fixture::rewrite("lifetime_fixture.rs", source, name, &Options::default());
+        fixture::rewritten(&rewrite)
+    }
+
+    #[test]
+    fn letters() {
+        let scheme = Scheme::default();
+        let declared: HashSet<String> = ["'a".to_owned(), "'c".to_owned()].into();
+        let mut lifetimes = Lifetimes::new(&scheme, declared);
+        assert_eq!(lifetimes.fresh().unwrap(), "'b");
+        assert_eq!(lifetimes.fresh().unwrap(), "'d");
+    }
+
+    #[test]
+    fn named_scheme() {
+        let scheme = Scheme::from_name("src").unwrap();
+        let declared: HashSet<String> = ["'src".to_owned()].into();
+        let mut lifetimes = Lifetimes::new(&scheme, declared);
+        assert_eq!(lifetimes.fresh().unwrap(), "'src1");
+        assert_eq!(lifetimes.fresh().unwrap(), "'src2");
+        assert_eq!(Scheme::from_name("static"), None);
+        assert_eq!(Scheme::from_name("1a"), None);
+    }
+
+    #[test]
+    fn exhausted() {
+        let scheme = Scheme::Letters;
+        let declared = ('a'..='z').map(|c| format!("'{c}")).collect();
+        let mut lifetimes = Lifetimes::new(&scheme, declared);
+        assert_eq!(lifetimes.fresh(), Err(Exhausted));
+    }
+
+    #[test]
+    fn elided_with_one_parameter() {
+        assert_eq!(
+            rewrite(
+                "fn f(s: String) -> String {\n    let t = s.clone();\n    if t.is_empty() { s } else { t }\n}",
+                "f"
+            ),
+            "fn f(s: &str) -> &str {\n    let t = s;\n    if t.is_empty() { s } else { t }\n}"
+        );
+    }
+
+    #[test]
+    fn named_with_two_parameters() {
+        // the elision rules give no lifetime to the return type
+        assert_eq!(
+            rewrite(
+                "fn f(s: String, t: String) -> String {\n    let u = s.clone();\n    let v = t.clone();\n    \
+                 if u.len() > v.len() { s } else { t }\n}",
+                "f"
+            ),
+            "fn f<'a>(s: &'a str, t: &'a str) -> &'a str {\n    let u = s;\n    let v = t;\n    \
+             if u.len() > v.len() { s } else { t }\n}"
+        );
+    }
+
+    #[test]
+    fn closure_in_body() {
+        // the closure is a body of its own, without a signature to elide in
+        assert_eq!(
+            rewrite(
+                "fn f(s: String) -> usize {\n    let t = s.clone();\n    \
+                 t.chars().filter(|c| *c == 'a').count() + s.len()\n}",
+                "f"
+            ),
+            "fn f(s: &str) -> usize {\n    let t = s;\n    \
+             t.chars().filter(|c| *c == 'a').count() + s.len()\n}"
+        );
+    }
+}
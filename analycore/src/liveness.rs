@@ -0,0 +1,144 @@
+//! Clones of a value that is not used afterwards, which is moved instead:
+//! `let b = a.clone(); consume(b);` becomes `let b = a; consume(b);`.
+
+use crate::borrowed;
+use rustc_borrowck::consumers::BodyWithBorrowckFacts;
+use rustc_index::{bit_set::BitSet, IndexVec};
+use rustc_middle::mir::{BasicBlock, Body, Local, Rvalue, StatementKind, TerminatorKind};
+use rustc_middle::ty::{GenericArgKind, RegionVid, TyCtxt};
+use rustc_mir_dataflow::{impls::MaybeLiveLocals, Analysis};
+
+/// Locals live at the start of each block.
+pub(crate) struct Liveness(IndexVec<BasicBlock, BitSet<Local>>);
+impl Liveness {
+    /// Liveness in `body`, where dropping a local is not a use of it: a
+    /// moved value is not dropped.
+    pub(crate) fn new<'tcx>(ctx: &TyCtxt<'tcx>, body: &Body<'tcx>) -> Self {
+        let mut body = body.clone();
+        for data in body.basic_blocks_mut() {
+            let terminator = data.terminator_mut();
+            if let TerminatorKind::Drop { target, .. } = terminator.kind {
+                terminator.kind = TerminatorKind::Goto { target };
+            }
+        }
+        let mut cursor = MaybeLiveLocals
+            .iterate_to_fixpoint(*ctx, &body, None)
+            .into_results_cursor(&body);
+        Self(
+            body.basic_blocks
+                .indices()
+                .map(|block| {
+                    cursor.seek_to_block_start(block);
+                    cursor.get().clone()
+                })
+                .collect(),
+        )
+    }
+
+    /// The local cloned by the call terminating `block`, if the clone is of
+    /// the same type and the local is dead once the call returns, borrows of
+    /// it included: `let r = &a; let b = a.clone(); r.len()` cannot move `a`.
+    pub(crate) fn moved<'tcx>(
+        &self,
+        ctx: &TyCtxt<'tcx>,
+        bck: &BodyWithBorrowckFacts<'tcx>,
+        block: BasicBlock,
+    ) -> Option<Local> {
+        let body = &bck.body;
+        let data = &body.basic_blocks[block];
+        let TerminatorKind::Call {
+            args,
+            destination,
+            target: Some(target),
+            ..
+        } = &data.terminator().kind
+        else {
+            return None;
+        };
+        let [arg] = &args[..] else {
+            return None;
+        };
+        let source = borrowed::borrowed_place(body, block, &arg.node)?.as_local()?;
+        let same_ty = ctx.erase_regions(body.local_decls[source].ty)
+            == ctx.erase_regions(destination.ty(&body.local_decls, *ctx).ty);
+        let dead =
+            same_ty && !self.0[*target].contains(source) && !self.borrowed(bck, source, *target);
+        dead.then_some(source)
+    }
+
+    /// Whether a borrow of `local` is live at the start of `block`: a live
+    /// local holds a region the region of the borrow outlives.
+    fn borrowed(&self, bck: &BodyWithBorrowckFacts<'_>, local: Local, block: BasicBlock) -> bool {
+        let body = &bck.body;
+        let regions = body.basic_blocks.iter().flat_map(|data| {
+            data.statements.iter().filter_map(|stmt| match &stmt.kind {
+                StatementKind::Assign(assign) => match &assign.1 {
+                    Rvalue::Ref(region, _, place) if place.local == local && region.is_var() => {
+                        Some(region.as_var())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+        });
+        let live: Vec<RegionVid> = self.0[block]
+            .iter()
+            .flat_map(|live| body.local_decls[live].ty.walk())
+            .filter_map(|arg| match arg.unpack() {
+                GenericArgKind::Lifetime(region) if region.is_var() => Some(region.as_var()),
+                _ => None,
+            })
+            .collect();
+        regions.into_iter().any(|borrow| {
+            live.iter()
+                .any(|region| bck.region_inference_context.eval_outlives(borrow, *region))
+        })
+    }
+
+    /// Whether `local` is live when a coroutine `body` resumes from a
+    /// suspension point, i.e. is held across an `.await`.
+    pub(crate) fn across_yield(&self, body: &Body<'_>, local: Local) -> bool {
+        body.basic_blocks
+            .iter()
+            .any(|data| match data.terminator().kind {
+                TerminatorKind::Yield { resume, .. } => self.0[resume].contains(local),
+                _ => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Strategy;
+    use crate::Options;
+
+    /// How the clones of the function `name` in `source` are eliminated.
+    fn strategies(source: &str, name: &str) -> Vec<Strategy> {
+        let rewrite = fixture::rewrite("liveness_fixture.rs", source, name, &Options::default());
+        rewrite
+            .eliminations
+            .iter()
+            .map(|elim| elim.strategy)
+            .collect()
+    }
+
+    #[test]
+    fn dead_after_clone() {
+        let source = "fn consume(_: String) {}\n\
+                      fn f(a: String) {\n    let b = a.clone();\n    consume(b);\n}";
+        assert_eq!(strategies(source, "f"), [Strategy::Move]);
+    }
+
+    #[test]
+    fn borrowed_after_clone() {
+        // `r` still borrows `a`, which is borrowed rather than moved
+        let source = "fn f(a: String) -> usize {\n    let r = &a;\n    let b = a.clone();\n    \
+                      let n = b.len();\n    n + r.len()\n}";
+        assert_eq!(strategies(source, "f"), [Strategy::Borrow]);
+        // as do the arguments of a format string
+        let source = "fn g(o: String) {\n    \
+                      println!(\"{} {}\", o, { let p = o.clone(); p.len() });\n}";
+        assert_eq!(strategies(source, "g"), [Strategy::Borrow]);
+    }
+}
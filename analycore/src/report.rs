@@ -0,0 +1,185 @@
+//! Per-clone report: every clone found, and whether and why it was removed,
+//! with the errors of the compiler for a clone kept as its removal does not
+//! compile.
+
+use crate::json::{line_column, string};
+use crate::rewrite::{Range, Rejection, Strategy};
+use crate::sources::SourceFile;
+use crate::Rewrite;
+
+/// Every clone of `rewrite` ordered by position, with the file it is in,
+/// and how it was removed or the reason it was kept.
+fn entries(rewrite: &Rewrite) -> Vec<(&SourceFile, Range, Result<Strategy, &Rejection>)> {
+    let mut entries: Vec<_> = rewrite
+        .eliminations
+        .iter()
+        .map(|elim| (elim.range, Ok(elim.strategy)))
+        .chain(
+            rewrite
+                .rejected
+                .iter()
+                .map(|rej| (rej.range, Err(&rej.reason))),
+        )
+        .collect();
+    entries.sort_by_key(|(range, _)| range.lo);
+    entries
+        .into_iter()
+        .filter_map(|(range, reason)| {
+            let (file, range) = rewrite.sources.local(range)?;
+            Some((file, range, reason))
+        })
+        .collect()
+}
+
+/// One line per clone, e.g. `src/lib.rs:4:18: kept: the clone is mutably borrowed`,
+/// followed by the compiler errors indented if the rewrite does not compile.
+pub fn human(rewrite: &Rewrite) -> String {
+    let mut out = String::new();
+    for (file, range, reason) in entries(rewrite) {
+        let path = file.path.to_string_lossy();
+        let (line, column) = line_column(&file.source, range.lo as usize);
+        let snippet = &file.source[range.lo as usize..range.hi as usize];
+        let status = match reason {
+            Ok(Strategy::Borrow) => "eliminated".to_owned(),
+            Ok(strategy) => format!("eliminated: {strategy}"),
+            Err(reason) => format!("kept: {reason}"),
+        };
+        out.push_str(&format!(
+            "{path}:{}:{}: `{snippet}` {status}\n",
+            line + 1,
+            column + 1
+        ));
+        for diagnostic in diagnostics(reason) {
+            out.push_str(&format!("    {diagnostic}\n"));
+        }
+    }
+    out
+}
+
+/// One JSON object per line and clone.
+pub fn json(rewrite: &Rewrite) -> String {
+    let mut out = String::new();
+    for (file, range, reason) in entries(rewrite) {
+        let path = file.path.to_string_lossy();
+        let (line, column) = line_column(&file.source, range.lo as usize);
+        let snippet = &file.source[range.lo as usize..range.hi as usize];
+        let eliminated = reason.is_ok();
+        let diagnostics: Vec<String> = diagnostics(reason).iter().map(|d| string(d)).collect();
+        let (strategy, reason, explanation) = match reason {
+            Ok(strategy) => (
+                string(strategy.code()),
+                "null".to_owned(),
+                "null".to_owned(),
+            ),
+            Err(reason) => (
+                "null".to_owned(),
+                string(reason.code()),
+                string(&reason.to_string()),
+            ),
+        };
+        out.push_str(&format!(
+            concat!(
+                r#"{{"file_name":{},"byte_start":{},"byte_end":{},"line":{},"column":{},"#,
+                r#""snippet":{},"eliminated":{},"strategy":{},"reason":{},"explanation":{},"#,
+                r#""diagnostics":[{}]}}"#,
+                "\n"
+            ),
+            string(&path),
+            range.lo,
+            range.hi,
+            line + 1,
+            column + 1,
+            string(snippet),
+            eliminated,
+            strategy,
+            reason,
+            explanation,
+            diagnostics.join(","),
+        ));
+    }
+    out
+}
+
+/// The compiler errors of the rewrite for a clone kept by `reason`.
+fn diagnostics(reason: Result<Strategy, &Rejection>) -> &[String] {
+    match reason {
+        Err(Rejection::VerificationFailed(diagnostics)) => diagnostics,
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::Options;
+    use serde_json::Value;
+
+    /// A clone borrowed, one copied, one kept up front and one kept as its
+    /// removal does not compile.
+    const SOURCE: &str = "fn f(s: String) -> usize {\n    let t = s.clone();\n    t.len() + s.len()\n}\n\
+                          fn n(n: u32) -> u32 {\n    n.clone() + 1\n}\n\
+                          fn spawned(s: String) -> usize {\n    let t = s.clone();\n    \
+                          std::thread::spawn(move || t.len());\n    s.len()\n}\n\
+                          fn owned(s: String) -> usize {\n    let t = s.clone();\n    let u: String = t;\n    \
+                          u.len() + s.len()\n}";
+
+    fn rewrite() -> Rewrite {
+        fixture::rewrite("report_fixture.rs", SOURCE, "--all", &Options::default())
+    }
+
+    #[test]
+    fn human_lines() {
+        assert_eq!(
+            human(&rewrite()),
+            "report_fixture.rs:2:14: `.clone()` eliminated\n\
+             report_fixture.rs:6:6: `.clone()` eliminated: the value is copied instead\n\
+             report_fixture.rs:9:14: `.clone()` kept: the clone is passed where `'static` is required\n\
+             report_fixture.rs:14:14: `.clone()` kept: the rewritten code does not compile\n    \
+             report_fixture.rs:15:21: 15:22: mismatched types\n"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let entries: Vec<Value> = json(&rewrite())
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let [borrowed, copied, spawned, owned] = &entries[..] else {
+            panic!("{entries:?}");
+        };
+        let clone = SOURCE.find(".clone()").unwrap();
+        assert_eq!(
+            borrowed,
+            &serde_json::json!({
+                "file_name": "report_fixture.rs",
+                "byte_start": clone,
+                "byte_end": clone + ".clone()".len(),
+                "line": 2,
+                "column": 14,
+                "snippet": ".clone()",
+                "eliminated": true,
+                "strategy": "borrow",
+                "reason": null,
+                "explanation": null,
+                "diagnostics": [],
+            })
+        );
+        assert_eq!(copied["strategy"], "copy");
+        assert_eq!(spawned["line"], 9);
+        assert_eq!(spawned["column"], 14);
+        assert_eq!(spawned["eliminated"], false);
+        assert_eq!(spawned["strategy"], Value::Null);
+        assert_eq!(spawned["reason"], "static_argument");
+        assert_eq!(
+            spawned["explanation"],
+            "the clone is passed where `'static` is required"
+        );
+        assert_eq!(owned["reason"], "verification_failed");
+        assert_eq!(
+            owned["diagnostics"],
+            serde_json::json!(["report_fixture.rs:15:21: 15:22: mismatched types"])
+        );
+    }
+}
@@ -1,20 +1,36 @@
+use crate::borrowed::{self, Form};
+use crate::call_site;
+use crate::closure;
+use crate::conversion::{owned, Conversions, Owned};
+use crate::copy;
+use crate::future;
+use crate::generics::ParamList;
+use crate::iter;
+use crate::lifetime::{Exhausted, Lifetimes, Scheme};
+use crate::liveness::Liveness;
+use crate::sink;
+use crate::sources::{SourceFile, Sources};
 use rustc_borrowck::consumers::{
     get_body_with_borrowck_facts, BodyWithBorrowckFacts, ConsumerOptions,
 };
 use rustc_hir::{
     def_id::{DefId, LocalDefId},
-    BodyId, FnSig, Generics,
+    BodyId, Generics,
 };
 use rustc_middle::{
     mir::{
         BindingForm, Body, BorrowKind, Local, LocalInfo, Operand, Rvalue, StatementKind,
-        TerminatorKind, UserTypeProjections, VarBindingForm,
+        TerminatorKind, UserTypeProjections, VarBindingForm, RETURN_PLACE,
+    },
+    ty::{
+        BoundRegionKind, BoundVariableKind, GenericArgKind, Ty, TyCtxt, TyKind, TypeVisitableExt,
     },
-    ty::{BoundRegionKind, BoundVariableKind, Ty, TyCtxt, TyKind},
 };
+use rustc_session::config::CrateType;
 use rustc_span::Span;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 pub fn borrowck<'a, 'tcx>(
     ctx: &'a TyCtxt<'tcx>,
@@ -23,13 +39,15 @@ pub fn borrowck<'a, 'tcx>(
     get_body_with_borrowck_facts(*ctx, def_id, ConsumerOptions::RegionInferenceContext)
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Range {
-    lo: u32,
-    hi: u32,
+/// Byte range in the source: in the `SourceMap` of the compilation it was
+/// found in, or in a file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Range {
+    pub lo: u32,
+    pub hi: u32,
 }
 impl Range {
-    fn new(lo: u32, hi: u32) -> Self {
+    pub fn new(lo: u32, hi: u32) -> Self {
         Self { lo, hi }
     }
 }
@@ -39,6 +57,12 @@ impl From<Span> for Range {
     }
 }
 
+/// The clone call whose `prefix`, and `suffix` if any, are removed: the whole
+/// of `Rc::clone(&x)`, or `.clone()` alone.
+fn call_range((prefix, suffix): (Range, Option<Range>)) -> Range {
+    Range::new(prefix.lo, suffix.map_or(prefix.hi, |suffix| suffix.hi))
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum LifetimeRelation {
     Eq,
@@ -144,14 +168,14 @@ struct V {
     // (variable, add ref to type, )
     relations: Vec<VarRelation>,
     ty: BTreeMap<Local, Vec<VarTypeRelation>>,
-    lifetime_annotation_id: u8,
+    lifetime_annotation_id: u32,
 }
 impl V {
     fn new() -> Self {
         Self {
             relations: Vec::new(),
             ty: BTreeMap::new(),
-            lifetime_annotation_id: b'a',
+            lifetime_annotation_id: 0,
         }
     }
     /*
@@ -237,7 +261,7 @@ impl V {
         log::debug!("{local:?} affect: {res:?}");
         res.into_keys().collect()
     }
-    fn new_annotation_id(&mut self) -> u8 {
+    fn new_annotation_id(&mut self) -> u32 {
         let annot = self.lifetime_annotation_id;
         self.lifetime_annotation_id += 1;
         annot
@@ -264,9 +288,27 @@ impl V {
         }
         false
     }
-    fn elim(&mut self, generics: &Generics<'_>, body: &Body<'_>) -> Substitutes {
+    /// Whether a borrow among `locals` was found not to outlive its target.
+    fn has_lifetime_conflict(&self, locals: &[Local]) -> bool {
+        self.relations.iter().any(|rel| match rel {
+            VarRelation::ImmRef {
+                right,
+                left,
+                lifetime: LifetimeRelation::InvalidOutlive,
+                ..
+            } => locals.contains(right) || locals.contains(left),
+            _ => false,
+        })
+    }
+    fn elim<'tcx>(
+        &mut self,
+        ctx: &TyCtxt<'tcx>,
+        generics: &Generics<'_>,
+        body: &Body<'tcx>,
+        conversions: &Conversions,
+        scheme: &Scheme,
+    ) -> (Vec<Elimination>, Vec<Rejected>) {
         log::info!("start calculation for S by using V");
-        let mut s = Substitutes::new();
         let mut candidates = Vec::new();
         for rel in &self.relations {
             match rel {
@@ -277,18 +319,60 @@ impl V {
             }
         }
         let mut clones = Vec::new();
+        let mut rejected = Vec::new();
         log::debug!("candidates: {candidates:?}");
         for rel in &candidates {
             match *rel {
-                VarRelation::Clone { from, to, .. } => {
-                    if !self.may_be_mutably_borrowed(from) && !self.may_be_mutably_borrowed(to)
+                VarRelation::Clone {
+                    from, to, range, ..
+                } => {
                     /*
                     if !self.may_be_moved(from)
                         && !self.may_be_mutably_borrowed(from)
                         && !self.may_be_moved(to)
                         && !self.may_be_mutably_borrowed(to)
                         */
+                    let def_id = body.source.def_id().expect_local();
+                    let retypes_signature = || {
+                        self.list_affected_local(from).iter().any(|local| {
+                            local.as_usize() <= body.arg_count
+                                && !body.local_decls[*local].ty.is_ref()
+                        })
+                    };
+                    let reason = if self.may_be_mutably_borrowed(from) {
+                        Some(Rejection::SourceMutablyBorrowed)
+                    } else if self.may_be_mutably_borrowed(to) {
+                        Some(Rejection::CloneMutablyBorrowed)
+                    } else if self.has_lifetime_conflict(&self.list_affected_local(from)) {
+                        Some(Rejection::LifetimeConflict)
+                    } else if (ctx.trait_of_item(def_id.to_def_id()).is_some()
+                        || ctx
+                            .impl_of_method(def_id.to_def_id())
+                            .is_some_and(|imp| ctx.trait_id_of_impl(imp).is_some()))
+                        && retypes_signature()
+                    {
+                        // the signature of a trait method is shared by the trait
+                        // and its impls
+                        Some(Rejection::TraitSignature)
+                    } else if is_exported(ctx, def_id) && retypes_signature() {
+                        // its callers in other crates are not rewritten
+                        Some(Rejection::ExportedSignature)
+                    } else if let Some(reason) = sink::required(ctx, def_id, body, to) {
+                        Some(reason)
+                    } else if owned(ctx, body.local_decls[to].ty) == Owned::Shared
+                        && borrowed::escapes(body, to)
                     {
+                        Some(Rejection::HandleEscapes)
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = reason {
+                        log::debug!("{rel:?} is not eliminated: {reason}");
+                        rejected.push(Rejected {
+                            range: call_range(range),
+                            reason,
+                        });
+                    } else {
                         clones.push(*rel);
                     }
                 }
@@ -297,6 +381,9 @@ impl V {
         }
         log::debug!("eliminate: {clones:?}");
         let mut affected = BTreeMap::new();
+        let mut forms_of = BTreeMap::new();
+        // (removed clone, its edits, locals to be retyped)
+        let mut eliminations = Vec::new();
         for clone in &clones {
             match clone {
                 VarRelation::Clone {
@@ -304,21 +391,20 @@ impl V {
                     to,
                     range: (r1, r2),
                 } => {
+                    let mut s = Substitutes::new();
                     s.rewrite(r1.lo, r1.hi, "".to_owned());
                     if let Some(r2) = r2 {
                         s.rewrite(r2.lo, r2.hi, "".to_owned());
                     }
                     let mut new_annotation = self.new_annotation_id();
                     let affect = self.list_affected_local(*from);
-                    for affect in affect {
+                    for affect in &affect {
                         log::debug!("{affect:?} affected by rewrite of {to:?}");
                         // 型が等しい場合にライフタイムアノテーションの関係を解決
-                        if let Some(annotation) = affected.get(&affect).map(|v| *v) {
+                        if let Some(annotation) = affected.get(affect).map(|v| *v) {
                             for (_, annot) in &mut affected {
                                 log::info!(
-                                    "lifetime annotation {} is unified to {}",
-                                    *annot as char,
-                                    new_annotation as char
+                                    "lifetime annotation {annot} is unified to {new_annotation}"
                                 );
                                 if *annot == new_annotation {
                                     *annot = annotation;
@@ -326,67 +412,408 @@ impl V {
                             }
                             new_annotation = annotation;
                         } else {
-                            affected.insert(affect, new_annotation);
+                            affected.insert(*affect, new_annotation);
                         }
                     }
+                    // the locals retyped together take the idiomatic form only if all can
+                    let owned = affect
+                        .iter()
+                        .filter(|local| !body.local_decls[**local].ty.is_ref());
+                    let forms: Vec<Form> = owned
+                        .clone()
+                        .map(|local| Form::of(ctx, body, *local, conversions))
+                        .collect();
+                    let form = Form::common(&forms);
+                    for local in owned {
+                        forms_of.entry(*local).or_insert(form);
+                    }
+                    eliminations.push((call_range((*r1, *r2)), s, affect));
                 }
                 _ => {}
             }
         }
         log::debug!("affected: {affected:?}");
-        let mut annotations = BTreeMap::new();
+        let def_id = body.source.def_id().expect_local();
+        // the annotations written in the source, and the annotation ids
+        let mut annotated = Vec::new();
         for (local, annot) in &affected {
             let decl = body.local_decls.get(*local).unwrap();
-            match decl.local_info() {
-                LocalInfo::User(BindingForm::Var(var)) => {
-                    if let Some(span) = &var.opt_ty_info {
-                        let range = Range::from(*span);
-                        s.rewrite(range.lo, range.lo, format!("&'{} ", *annot as char));
-                        annotations.insert(*annot, ());
+            if decl.ty.is_ref() {
+                // already a borrow
+                continue;
+            }
+            let Some(span) = annotation(body, *local) else {
+                continue;
+            };
+            let Ok(ty) = ctx.sess.source_map().span_to_snippet(span) else {
+                continue;
+            };
+            annotated.push((*local, *annot, Range::from(span), ty));
+        }
+        let named = named_annotations(ctx, def_id, body, &annotated);
+        let mut lifetimes = Lifetimes::in_scope_of(ctx, scheme, def_id);
+        let mut annotations: BTreeMap<u32, String> = BTreeMap::new();
+        let mut retyped = BTreeMap::new();
+        // locals left owned for want of a lifetime name
+        let mut unnamed = Vec::new();
+        for (local, annot, range, ty) in annotated {
+            let lifetime = match annotations.get(&annot) {
+                Some(lifetime) => lifetime.clone(),
+                None if !named.contains(&annot) => String::new(),
+                None => match lifetimes.fresh() {
+                    Ok(lifetime) => {
+                        annotations.insert(annot, lifetime.clone());
+                        lifetime
+                    }
+                    Err(Exhausted) => {
+                        log::debug!("no lifetime name left for {local:?}");
+                        unnamed.push(local);
+                        continue;
                     }
+                },
+            };
+            let form = forms_of.get(&local).copied().unwrap_or(Form::Ref);
+            let (form, retype) = match form.retype(&lifetime, &ty) {
+                Some(retype) => (form, retype),
+                None => (Form::Ref, borrowed::reference(&lifetime, &ty)),
+            };
+            retyped.insert(local, (range.lo, range.hi, retype, form));
+        }
+        let lifetimes: Vec<String> = annotations.into_values().collect();
+        let generics_rewrite =
+            (!lifetimes.is_empty()).then(|| ParamList::of(generics).declare(&lifetimes));
+        let (eliminations, unnamed): (Vec<_>, Vec<_>) = eliminations
+            .into_iter()
+            .partition(|(_, _, affect)| !affect.iter().any(|local| unnamed.contains(local)));
+        rejected.extend(unnamed.into_iter().map(|(range, _, _)| Rejected {
+            range,
+            reason: Rejection::LifetimesExhausted,
+        }));
+        let eliminations = eliminations
+            .into_iter()
+            .map(|(range, mut s, affect)| {
+                let mut any_retyped = false;
+                for local in &affect {
+                    if let Some((lo, hi, ty, _)) = retyped.get(local) {
+                        s.rewrite(*lo, *hi, ty.clone());
+                        any_retyped = true;
+                    }
+                }
+                if let (true, Some((pos, params))) = (any_retyped, &generics_rewrite) {
+                    s.rewrite(*pos, *pos, params.clone());
                 }
-                _ => {
-                    let range = Range::from(decl.source_info.span);
-                    if range.lo != range.hi {
-                        s.rewrite(range.lo, range.lo, format!("&'{} ", *annot as char));
-                        annotations.insert(*annot, ());
+                let params = affect
+                    .iter()
+                    .filter(|local| (1..=body.arg_count).contains(&local.as_usize()))
+                    .filter_map(|local| Some((local.as_usize() - 1, retyped.get(local)?.3)))
+                    .collect();
+                // unified by the loop above, the annotation of one is the one of all
+                let group = affect.first().and_then(|local| affected.get(local));
+                Elimination {
+                    range,
+                    substitutes: s,
+                    params,
+                    strategy: Strategy::Borrow,
+                    group: group.map(|annot| (def_id, *annot)),
+                }
+            })
+            .collect();
+        (eliminations, rejected)
+    }
+}
+
+/// The annotation ids of `annotated`, the locals of the function `def_id`
+/// retyped to borrows, that need a named lifetime parameter: none but the
+/// ones of the return type, and not those if the lifetime elision rules
+/// give it the one lifetime of the parameters. `fn f(s: String) -> String`
+/// becomes `fn f(s: &str) -> &str`, `fn f(&self, s: String) -> String`
+/// becomes `fn f<'a>(&self, s: &'a str) -> &'a str`, and so does
+/// `fn f(s: String, t: String) -> String` to `fn f<'a>(s: &'a str, t: &'a str) -> &'a str`.
+fn named_annotations<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+    annotated: &[(Local, u32, Range, String)],
+) -> BTreeSet<u32> {
+    let of = |locals: &dyn Fn(Local) -> bool| -> BTreeSet<u32> {
+        annotated
+            .iter()
+            .filter(|(local, ..)| locals(*local))
+            .map(|(_, annot, ..)| *annot)
+            .collect()
+    };
+    let is_param = |local: Local| (1..=body.arg_count).contains(&local.as_usize());
+    let returned = of(&|local| local == RETURN_PLACE);
+    // a closure has no signature of its own to elide lifetimes in
+    if returned.is_empty() || ctx.is_closure_like(def_id.to_def_id()) {
+        return returned;
+    }
+    let params = of(&is_param);
+    // each parameter retyped to a borrow takes a lifetime of its own
+    let retyped = annotated
+        .iter()
+        .filter(|(local, ..)| is_param(*local))
+        .count();
+    let elided = returned.is_subset(&params) && retyped + param_lifetimes(ctx, def_id) == 1;
+    if elided {
+        BTreeSet::new()
+    } else {
+        returned
+    }
+}
+
+/// The type annotation of `local` in the source, if it has one.
+fn annotation(body: &Body<'_>, local: Local) -> Option<Span> {
+    let decl = &body.local_decls[local];
+    let span = match decl.local_info() {
+        LocalInfo::User(BindingForm::Var(var)) => var.opt_ty_info,
+        // temporaries have no type in the source, only parameters
+        // like `self` do
+        _ if local.as_usize() <= body.arg_count => Some(decl.source_info.span),
+        _ => None,
+    };
+    span.filter(|span| !span.is_empty())
+}
+
+/// Whether the clone into `to` is a temporary passed straight to a function
+/// taking the owned type itself, as in `f(x.clone())` with `fn f(x: String)`:
+/// without the clone, `x` is moved or mistyped, until the rewrite of `f`
+/// passes `&x` instead.
+fn passed_owned<'tcx>(ctx: &TyCtxt<'tcx>, body: &Body<'tcx>, to: Local) -> bool {
+    if body.local_decls[to].is_user_variable() {
+        return false;
+    }
+    borrowed::uses(body, to).iter().any(|(_, location)| {
+        if borrowed::statement(body, *location).is_some() {
+            return false;
+        }
+        let TerminatorKind::Call { func, args, .. } =
+            &body.basic_blocks[location.block].terminator().kind
+        else {
+            return false;
+        };
+        let Some((callee, _)) = func.const_fn_def() else {
+            return false;
+        };
+        let sig = ctx.fn_sig(callee).instantiate_identity().skip_binder();
+        // a generic parameter may take a borrow as well
+        args.iter().zip(sig.inputs()).any(|(arg, input)| {
+            matches!(arg.node, Operand::Move(place) if place.as_local() == Some(to))
+                && !input.has_param()
+        })
+    })
+}
+
+/// Whether the function `def_id` can be called from other crates: an
+/// executable exports nothing.
+fn is_exported(ctx: &TyCtxt<'_>, def_id: LocalDefId) -> bool {
+    !ctx.crate_types()
+        .iter()
+        .all(|ty| *ty == CrateType::Executable)
+        && ctx.effective_visibilities(()).is_exported(def_id)
+}
+
+/// The number of lifetimes in the parameters of the function `def_id`,
+/// elided or not, as counted by the lifetime elision rules.
+fn param_lifetimes(ctx: &TyCtxt<'_>, def_id: LocalDefId) -> usize {
+    let sig = ctx.liberate_late_bound_regions(
+        def_id.to_def_id(),
+        ctx.fn_sig(def_id).instantiate_identity(),
+    );
+    let mut regions = Vec::new();
+    for ty in sig.inputs() {
+        for arg in ty.walk() {
+            match arg.unpack() {
+                // bound by a `for<'a>` in the parameter
+                GenericArgKind::Lifetime(region) if !region.is_bound() => {
+                    if !regions.contains(&region) {
+                        regions.push(region);
                     }
                 }
+                _ => {}
             }
         }
-        if 0 < annotations.len() {
-            let mut annot_str = String::new();
-            for (annot, _) in annotations {
-                annot_str.push('\'');
-                annot_str.push(annot as char);
-                annot_str.push(',');
+    }
+    regions.len()
+}
+
+/// Why a clone is kept.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Rejection {
+    /// the cloned value is mutably borrowed
+    SourceMutablyBorrowed,
+    /// the clone is mutably borrowed
+    CloneMutablyBorrowed,
+    /// a borrow would not live long enough
+    LifetimeConflict,
+    /// the cloned `Rc` or `Arc` is returned, stored or sent to another thread
+    HandleEscapes,
+    /// the cloned elements of an iterator are used by value
+    ElementsConsumed,
+    /// the clone is used by value where it is made
+    CloneConsumed,
+    /// a borrow held across an `.await` would make the future not `Send`
+    HeldAcrossAwait,
+    /// the clone is passed to an argument bound by `'static`
+    StaticArgument,
+    /// the clone is passed to an argument bound by `Send`, which a borrow
+    /// of it is not
+    SendArgument,
+    /// the borrow needs a lifetime parameter, and every name is declared
+    LifetimesExhausted,
+    /// a parameter or the return type of a trait method, provided or
+    /// implemented, would be retyped to a borrow
+    TraitSignature,
+    /// a parameter or the return type of a function other crates can call
+    /// would be retyped to a borrow
+    ExportedSignature,
+    /// the struct whose fields would borrow is named in the type of a field
+    /// or another item, which cannot take an elided lifetime
+    NamedInItem,
+    /// the edits of the rewrite change text another rewrite changes
+    EditConflict,
+    /// the rewritten code does not compile, with the errors of the compiler
+    VerificationFailed(Vec<String>),
+}
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Rejection::SourceMutablyBorrowed => "the cloned value is mutably borrowed",
+            Rejection::CloneMutablyBorrowed => "the clone is mutably borrowed",
+            Rejection::LifetimeConflict => {
+                "a borrow of the cloned value would not live long enough"
             }
-            let range = Range::from(generics.span);
-            if 0 < range.hi - range.lo {
-                s.rewrite(range.hi - 1, range.hi - 1, format!(",{annot_str}"));
-            } else {
-                s.rewrite(range.hi, range.hi, format!("<{annot_str}>"));
+            Rejection::HandleEscapes => {
+                "the cloned handle outlives the original or is sent to another thread"
+            }
+            Rejection::ElementsConsumed => "the cloned elements are used by value",
+            Rejection::CloneConsumed => "the clone is used by value",
+            Rejection::HeldAcrossAwait => {
+                "a borrow held across an `.await` would make the future not `Send`"
+            }
+            Rejection::StaticArgument => "the clone is passed where `'static` is required",
+            Rejection::SendArgument => {
+                "the clone is passed where `Send` is required, which a borrow of it is not"
             }
+            Rejection::LifetimesExhausted => "no lifetime name is left for the borrow",
+            Rejection::TraitSignature => "the borrow would change the signature of a trait method",
+            Rejection::ExportedSignature => {
+                "the borrow would change the signature of a function exported by the crate"
+            }
+            Rejection::NamedInItem => {
+                "the struct is named in the type of an item, where `'_` is not allowed"
+            }
+            Rejection::EditConflict => "its edits conflict with those of another rewrite",
+            Rejection::VerificationFailed(_) => "the rewritten code does not compile",
+        };
+        f.write_str(reason)
+    }
+}
+impl Rejection {
+    /// Stable identifier used in the JSON report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::SourceMutablyBorrowed => "source_mutably_borrowed",
+            Rejection::CloneMutablyBorrowed => "clone_mutably_borrowed",
+            Rejection::LifetimeConflict => "lifetime_conflict",
+            Rejection::HandleEscapes => "handle_escapes",
+            Rejection::ElementsConsumed => "elements_consumed",
+            Rejection::CloneConsumed => "clone_consumed",
+            Rejection::HeldAcrossAwait => "held_across_await",
+            Rejection::StaticArgument => "static_argument",
+            Rejection::SendArgument => "send_argument",
+            Rejection::LifetimesExhausted => "lifetimes_exhausted",
+            Rejection::TraitSignature => "trait_signature",
+            Rejection::ExportedSignature => "exported_signature",
+            Rejection::NamedInItem => "named_in_item",
+            Rejection::EditConflict => "edit_conflict",
+            Rejection::VerificationFailed(_) => "verification_failed",
         }
-        s
     }
 }
 
-pub fn rewrite<'a, 'hir, 'tcx>(
-    source: String,
-    ctx: &'a TyCtxt<'tcx>,
-    signature: FnSig<'hir>,
-    generics: Generics<'hir>,
-    def_id: LocalDefId,
-) -> Result<Option<String>, ()> {
-    let clone_local_id = ctx.lang_items().clone_fn().unwrap();
+/// A clone kept in the body.
+#[derive(Clone, Debug)]
+pub struct Rejected {
+    /// the `.clone()`
+    pub range: Range,
+    pub reason: Rejection,
+}
+
+/// A clone removed from a body, with every edit the removal requires.
+#[derive(Clone, Debug)]
+pub struct Elimination {
+    /// the removed `.clone()`
+    pub range: Range,
+    pub substitutes: Substitutes,
+    /// parameters (0-based, `self` included) retyped to a borrow, and how
+    pub params: Vec<(usize, Form)>,
+    pub strategy: Strategy,
+    /// the body and the lifetime annotation of the locals retyped to a
+    /// borrow: the eliminations sharing it retype the same locals, and are
+    /// only valid together
+    pub group: Option<(LocalDefId, u32)>,
+}
+
+/// How the value of a removed clone is obtained instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Strategy {
+    /// the clone becomes a borrow of the cloned value
+    Borrow,
+    /// the cloned value is not used afterwards and moved
+    Move,
+    /// the cloned value is `Copy` and copied
+    Copy,
+    /// the fields of a struct always initialized by clones borrow instead
+    Field,
+}
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strategy = match self {
+            Strategy::Borrow => "the value is borrowed instead",
+            Strategy::Move => "the value is moved instead",
+            Strategy::Copy => "the value is copied instead",
+            Strategy::Field => "its fields borrow the cloned values instead",
+        };
+        f.write_str(strategy)
+    }
+}
+impl Strategy {
+    /// Stable identifier used in the JSON report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Strategy::Borrow => "borrow",
+            Strategy::Move => "move",
+            Strategy::Copy => "copy",
+            Strategy::Field => "field",
+        }
+    }
+}
 
+/// Computes the clone eliminations in the body of `def_id`, and the clones
+/// that have to be kept.
+pub fn eliminations(
+    ctx: &TyCtxt<'_>,
+    generics: &Generics<'_>,
+    def_id: LocalDefId,
+    conversions: &Conversions,
+    scheme: &Scheme,
+) -> (Vec<Elimination>, Vec<Rejected>) {
+    if iter::is_element_clone(ctx, def_id) {
+        log::debug!("{def_id:?} is eliminated with the iterator it maps");
+        return (Vec::new(), Vec::new());
+    }
     let bck = borrowck(ctx, def_id);
     let body = &bck.body;
 
     log::debug!("MIR basic blocks:\n{:?}", body.basic_blocks);
 
     let mut v = V::new();
+    let mut liveness = None;
+    let is_closure = ctx.is_closure_like(def_id.to_def_id());
+    let mut kept = Vec::new();
+    // clones removed without borrowing
+    let mut unborrowed = Vec::new();
 
     let get_opr_rel = |opr, left| match opr {
         &Operand::Copy(r) => Some(VarRelation::Copy {
@@ -426,7 +853,7 @@ pub fn rewrite<'a, 'hir, 'tcx>(
             _ => None,
         }
     };
-    for bb in body.basic_blocks.iter() {
+    for (block, bb) in body.basic_blocks.iter_enumerated() {
         for stmt in bb.statements.iter() {
             match &stmt.kind {
                 StatementKind::Assign(a) => {
@@ -538,18 +965,112 @@ pub fn rewrite<'a, 'hir, 'tcx>(
                     call_source,
                     fn_span,
                 } => {
-                    if let Some((def_id, _)) = func.const_fn_def() {
-                        if def_id == clone_local_id {
+                    if let Some((callee, _)) = func.const_fn_def() {
+                        let conversion = match &args[..] {
+                            [arg] => conversions.borrow(
+                                ctx,
+                                callee,
+                                arg.node.ty(&body.local_decls, *ctx),
+                                destination.ty(&body.local_decls, *ctx).ty,
+                            ),
+                            _ => None,
+                        };
+                        let is_deref = ctx.trait_of_item(callee).is_some()
+                            && ctx.trait_of_item(callee) == ctx.lang_items().deref_trait();
+                        if conversion.is_some() {
                             let arg = &args[0];
                             let range;
                             if fn_span.lo().0 < arg.span.lo().0 {
+                                // `Rc::clone(&x)` leaves `x`, as `x.clone()` does
+                                let borrow = ctx
+                                    .sess
+                                    .source_map()
+                                    .span_to_snippet(arg.span)
+                                    .ok()
+                                    .and_then(|arg| {
+                                        let value = arg.strip_prefix('&')?.trim_start();
+                                        (!value.starts_with("mut "))
+                                            .then_some((arg.len() - value.len()) as u32)
+                                    })
+                                    .unwrap_or(0);
                                 range = (
-                                    Range::new(fn_span.lo().0, arg.span.lo().0),
+                                    Range::new(fn_span.lo().0, arg.span.lo().0 + borrow),
                                     Some(Range::new(arg.span.hi().0, fn_span.hi().0)),
                                 );
                             } else {
                                 range = (Range::new(arg.span.hi().0, fn_span.hi().0), None);
                             }
+                            let to = ctx.erase_regions(destination.ty(&body.local_decls, *ctx).ty);
+                            let copied = to
+                                .is_copy_modulo_regions(*ctx, body.typing_env(*ctx))
+                                .then(|| copy::copy(ctx, def_id, *fn_span))
+                                .flatten();
+                            if let Some(s) = copied {
+                                log::info!("{to:?} is Copy; copied instead");
+                                unborrowed.push(Elimination {
+                                    range: call_range(range),
+                                    substitutes: s,
+                                    params: Vec::new(),
+                                    strategy: Strategy::Copy,
+                                    group: None,
+                                });
+                                continue;
+                            }
+                            let moved = liveness
+                                .get_or_insert_with(|| Liveness::new(ctx, body))
+                                .moved(ctx, &bck, block);
+                            if let Some(source) = moved {
+                                log::info!("{source:?} is dead after the clone; moved instead");
+                                let mut s = Substitutes::new();
+                                s.rewrite(range.0.lo, range.0.hi, "".to_owned());
+                                if let Some(r2) = range.1 {
+                                    s.rewrite(r2.lo, r2.hi, "".to_owned());
+                                }
+                                unborrowed.push(Elimination {
+                                    range: call_range(range),
+                                    substitutes: s,
+                                    params: Vec::new(),
+                                    strategy: Strategy::Move,
+                                    group: None,
+                                });
+                                continue;
+                            }
+                            if is_closure {
+                                let liveness =
+                                    liveness.get_or_insert_with(|| Liveness::new(ctx, body));
+                                let borrowed = closure::borrow_in_place(ctx, def_id, body, block)
+                                    .and_then(|s| {
+                                        let local = destination.local;
+                                        future::check_borrow(ctx, def_id, body, liveness, local)
+                                            .map(|()| s)
+                                    });
+                                match borrowed {
+                                    Ok(s) => unborrowed.push(Elimination {
+                                        range: call_range(range),
+                                        substitutes: s,
+                                        params: Vec::new(),
+                                        strategy: Strategy::Borrow,
+                                        group: None,
+                                    }),
+                                    Err(reason) => {
+                                        log::debug!("{range:?} is not eliminated: {reason}");
+                                        kept.push(Rejected {
+                                            range: call_range(range),
+                                            reason,
+                                        });
+                                    }
+                                }
+                                continue;
+                            }
+                            if passed_owned(ctx, body, destination.local) {
+                                let reason = Rejection::CloneConsumed;
+                                log::debug!("{range:?} is not eliminated: {reason}");
+                                kept.push(Rejected {
+                                    range: call_range(range),
+                                    reason,
+                                });
+                                continue;
+                            }
                             match arg.node {
                                 Operand::Move(a) => {
                                     v.push(VarRelation::Clone {
@@ -567,6 +1088,15 @@ pub fn rewrite<'a, 'hir, 'tcx>(
                                 }
                                 _ => {}
                             }
+                        } else if let Some(arg) = args.first().filter(|_| is_deref) {
+                            // `Vec` to slice and such: the target borrows the same value
+                            if let Some(place) = arg.node.place() {
+                                v.push(VarRelation::Move {
+                                    right: place.local,
+                                    left: Some(destination.local),
+                                    range: Range::from(arg.span),
+                                });
+                            }
                         } else {
                             /*
                              * 手元でライフタイム注釈を確認する必要があったら書く
@@ -599,7 +1129,22 @@ pub fn rewrite<'a, 'hir, 'tcx>(
         }
     }
     log::debug!("{v:?}");
-    let s = v.elim(&generics, &body);
+    let (mut eliminations, mut rejected) = v.elim(ctx, generics, body, conversions, scheme);
+    eliminations.extend(unborrowed);
+    rejected.extend(kept);
+    let (elements, kept) = iter::eliminations(ctx, def_id, body);
+    eliminations.extend(elements);
+    rejected.extend(kept);
+    for elim in &mut eliminations {
+        if !elim.params.is_empty() {
+            elim.substitutes.merge(&call_site::borrow_args(
+                ctx,
+                def_id,
+                &elim.params,
+                conversions,
+            ));
+        }
+    }
 
     /*
     'arg: for arg_local in body.args_iter() {
@@ -816,44 +1361,258 @@ pub fn rewrite<'a, 'hir, 'tcx>(
         s.merge(&sa);
     }
     */
-    Ok(Some(s.apply(source)))
+    (eliminations, rejected)
 }
 
 fn source_slice<'a>(source: &'a str, from: i32, until: i32) -> &'a str {
     source.split_at(until as usize).0.split_at(from as usize).1
 }
 
-struct Substitutes {
+/// Why edits cannot be applied to a source.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditError {
+    /// the edit of `from..until` is reversed or past the end of the source
+    OutOfBounds(Range),
+    /// the offset is inside a character
+    NotCharBoundary(u32),
+    /// two edits change the same text
+    Overlap(Range, Range),
+    /// the edit at the offset is in none of the source files of the crate
+    OutsideSources(u32),
+}
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::OutOfBounds(range) => {
+                write!(
+                    f,
+                    "the edit of {}..{} is out of the source",
+                    range.lo, range.hi
+                )
+            }
+            EditError::NotCharBoundary(offset) => {
+                write!(f, "the edit at {offset} is inside a character")
+            }
+            EditError::Overlap(first, second) => write!(
+                f,
+                "the edits of {}..{} and {}..{} overlap",
+                first.lo, first.hi, second.lo, second.hi
+            ),
+            EditError::OutsideSources(offset) => {
+                write!(f, "the edit at {offset} is in no source file of the crate")
+            }
+        }
+    }
+}
+
+/// Text replacements `(from, until, insert)` on the source. A replacement
+/// made twice is applied once; insertions at the same offset are applied in
+/// the order they were made.
+#[derive(Clone, Debug, Default)]
+pub struct Substitutes {
     replaces: Vec<(u32, u32, String)>,
 }
 impl Substitutes {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             replaces: Vec::new(),
         }
     }
-    fn rewrite(&mut self, from: u32, until: u32, insert: String) {
-        self.replaces.push((from, until, insert));
+    pub(crate) fn rewrite(&mut self, from: u32, until: u32, insert: String) {
+        let replace = (from, until, insert);
+        if !self.replaces.contains(&replace) {
+            self.replaces.push(replace);
+        }
+    }
+    /// Adds the replacements of `other`; the ones already present are kept once.
+    pub fn merge(&mut self, other: &Self) {
+        for (from, until, insert) in &other.replaces {
+            self.rewrite(*from, *until, insert.clone());
+        }
     }
-    fn merge(&mut self, other: &Self) {
-        self.replaces.extend_from_slice(&other.replaces);
+    pub fn is_empty(&self) -> bool {
+        self.replaces.is_empty()
     }
-    fn apply(&self, mut target: String) -> String {
+    /// Whether a replacement, not an insertion, changes the whole of `range`.
+    pub fn covers(&self, range: Range) -> bool {
+        self.replaces
+            .iter()
+            .any(|(from, until, _)| from < until && *from <= range.lo && range.hi <= *until)
+    }
+    /// Replacements ordered by their range, so that insertions at an offset
+    /// precede a replacement starting there and keep the order they were made
+    /// in.
+    pub fn sorted(&self) -> Vec<(u32, u32, String)> {
         let mut sorted = self.replaces.clone();
-        let mut diff = 0i32;
-        sorted.sort_by(|(a_from, _, _), (b_from, _, _)| a_from.cmp(b_from));
-        for (from, until, insert) in sorted {
-            let (head, tail) = target.split_at((until as i32 + diff) as usize);
-            //target = tail.to_owned();
-            let (head, _) = head.split_at((from as i32 + diff) as usize);
+        sorted.sort_by_key(|(from, until, _)| (*from, *until));
+        sorted
+    }
+    /// The replacements, ordered as by [`Substitutes::sorted`], if they can
+    /// be applied to `source`: each within it and on character boundaries,
+    /// and none changing text another replaces or inserts into.
+    pub fn validate(&self, source: &str) -> Result<Vec<(u32, u32, String)>, EditError> {
+        let sorted = self.sorted();
+        let mut last: Option<Range> = None;
+        for (from, until, _) in &sorted {
+            let range = Range::new(*from, *until);
+            if from > until || *until as usize > source.len() {
+                return Err(EditError::OutOfBounds(range));
+            }
+            if let Some(offset) = [*from, *until]
+                .into_iter()
+                .find(|offset| !source.is_char_boundary(*offset as usize))
+            {
+                return Err(EditError::NotCharBoundary(offset));
+            }
+            match last {
+                Some(last) if range.lo < last.hi => return Err(EditError::Overlap(last, range)),
+                Some(last) if range.hi <= last.hi => {}
+                _ => last = Some(range),
+            }
+        }
+        Ok(sorted)
+    }
+    /// The replacements, at offsets in the `SourceMap`, split into one set
+    /// per file of `sources` they edit, at offsets in the file.
+    pub fn by_file<'s>(
+        &self,
+        sources: &'s Sources,
+    ) -> Result<Vec<(&'s SourceFile, Substitutes)>, EditError> {
+        let mut files: Vec<(&SourceFile, Substitutes)> = Vec::new();
+        for (from, until, insert) in &self.replaces {
+            let range = Range::new(*from, *until);
+            let (file, range) = sources
+                .local(range)
+                .ok_or(EditError::OutsideSources(*from))?;
+            match files.iter_mut().find(|(f, _)| f.path == file.path) {
+                Some((_, s)) => s.rewrite(range.lo, range.hi, insert.clone()),
+                None => {
+                    let mut s = Substitutes::new();
+                    s.rewrite(range.lo, range.hi, insert.clone());
+                    files.push((file, s));
+                }
+            }
+        }
+        Ok(files)
+    }
+    pub fn apply(&self, source: &str) -> Result<String, EditError> {
+        let mut target = String::with_capacity(source.len());
+        let mut pos = 0;
+        for (from, until, insert) in self.validate(source)? {
             log::info!(
                 "rewrite \"{}\" to \"{}\"",
-                source_slice(&target, from as i32 + diff, until as i32 + diff),
+                source_slice(source, from as i32, until as i32),
                 insert
             );
-            target = format!("{}{}{}", head, insert, tail);
-            diff += (from as i32) - (until as i32) + (insert.len() as i32);
+            target.push_str(&source[pos..from as usize]);
+            target.push_str(&insert);
+            pos = until as usize;
+        }
+        target.push_str(&source[pos..]);
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture;
+    use crate::Options;
+
+    const PATH: &str = "rewrite_fixture.rs";
+
+    fn substitutes(replaces: &[(u32, u32, &str)]) -> Substitutes {
+        let mut s = Substitutes::new();
+        for (from, until, insert) in replaces {
+            s.rewrite(*from, *until, insert.to_string());
         }
-        target
+        s
+    }
+
+    #[test]
+    fn overlapping_replacements() {
+        let s = substitutes(&[(4, 8, "b"), (0, 6, "a")]);
+        assert_eq!(
+            s.apply("let x = y;"),
+            Err(EditError::Overlap(Range::new(0, 6), Range::new(4, 8)))
+        );
+    }
+
+    #[test]
+    fn insertion_inside_replacement() {
+        let s = substitutes(&[(9, 17, ""), (12, 12, "&")]);
+        assert_eq!(
+            s.apply("let t = s.clone();"),
+            Err(EditError::Overlap(Range::new(9, 17), Range::new(12, 12)))
+        );
+        // at either end, it is not
+        let s = substitutes(&[(9, 17, ""), (9, 9, "["), (17, 17, "]")]);
+        assert_eq!(s.apply("let t = s.clone();").unwrap(), "let t = s[];");
+    }
+
+    #[test]
+    fn non_ascii_offset() {
+        // `é` is two bytes, 9..11
+        let source = "let t = \"é\".clone();";
+        let s = substitutes(&[(10, 10, "x")]);
+        assert_eq!(s.apply(source), Err(EditError::NotCharBoundary(10)));
+        let s = substitutes(&[(12, 20, "")]);
+        assert_eq!(s.apply(source).unwrap(), "let t = \"é\";");
+        let s = substitutes(&[(12, 40, "")]);
+        assert_eq!(
+            s.apply(source),
+            Err(EditError::OutOfBounds(Range::new(12, 40)))
+        );
+    }
+
+    #[test]
+    fn insertions_at_one_offset() {
+        // in the order they are made, before a replacement starting there,
+        // and once if made twice
+        let s = substitutes(&[
+            (4, 5, "T"),
+            (4, 4, "&"),
+            (4, 4, "'a "),
+            (4, 4, "&"),
+            (0, 0, "//\n"),
+        ]);
+        assert_eq!(s.apply("let x;").unwrap(), "//\nlet &'a T;");
+    }
+
+    #[test]
+    fn shared_handle() {
+        let source = "use std::sync::Arc;\n\
+                      fn f(s: Arc<String>) -> usize {\n    let t = Arc::clone(&s);\n    t.len() + s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert_eq!(
+            fixture::rewritten(&rewrite),
+            "use std::sync::Arc;\n\
+             fn f(s: &str) -> usize {\n    let t = s;\n    t.len() + s.len()\n}"
+        );
+        // the whole call is reported, not the prefix removed
+        let [elim] = &rewrite.eliminations[..] else {
+            panic!("{:?}", rewrite.eliminations);
+        };
+        let (file, range) = rewrite.sources.local(elim.range).unwrap();
+        assert_eq!(
+            &file.source[range.lo as usize..range.hi as usize],
+            "Arc::clone(&s)"
+        );
+    }
+
+    #[test]
+    fn handle_escapes() {
+        let source = "use std::sync::Arc;\n\
+                      struct Keep {\n    s: Arc<String>,\n}\n\
+                      fn stored(s: Arc<String>) -> Keep {\n    let t = Arc::clone(&s);\n    \
+                      let _n = s.len();\n    Keep { s: t }\n}\n\
+                      fn returned(s: &Arc<String>) -> Arc<String> {\n    let t = Arc::clone(s);\n    t\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(
+            reasons,
+            [&Rejection::HandleEscapes, &Rejection::HandleEscapes]
+        );
     }
 }
@@ -0,0 +1,129 @@
+//! Arguments a callee requires to own their value, by a `'static` or `Send`
+//! bound on their type as on the closure of `std::thread::spawn`. A clone
+//! moved into one, directly or captured by a closure, cannot be replaced by
+//! a borrow: `let t = s.clone(); thread::spawn(move || t.len())` is kept.
+
+use crate::borrowed;
+use crate::future;
+use crate::rewrite::Rejection;
+use rustc_hir::def_id::{DefId, LocalDefId};
+use rustc_middle::mir::{Body, Local, Operand, Rvalue, StatementKind, TerminatorKind};
+use rustc_middle::ty::{ClauseKind, TyCtxt, TyKind};
+use rustc_span::sym;
+
+/// Why the clone into `local` of the body `def_id` has to be kept, if its
+/// value is passed, as is or in a closure or aggregate, to an argument
+/// required to be `'static`, or `Send` when a borrow of it is not.
+pub(crate) fn required<'tcx>(
+    ctx: &TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+    local: Local,
+) -> Option<Rejection> {
+    let ty = body.local_decls[local].ty;
+    let mut visited = vec![local];
+    let mut locals = vec![local];
+    while let Some(local) = locals.pop() {
+        for (_, location) in borrowed::uses(body, local) {
+            let holds = |operand: &Operand<'_>| operand.place().is_some_and(|p| p.local == local);
+            match borrowed::statement(body, location) {
+                Some(StatementKind::Assign(assign)) => {
+                    let moved = match &assign.1 {
+                        Rvalue::Use(operand) | Rvalue::Cast(_, operand, _) => holds(operand),
+                        Rvalue::Aggregate(_, operands) => operands.iter().any(holds),
+                        _ => false,
+                    };
+                    if moved && !visited.contains(&assign.0.local) {
+                        visited.push(assign.0.local);
+                        locals.push(assign.0.local);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    let TerminatorKind::Call { func, args, .. } =
+                        &body.basic_blocks[location.block].terminator().kind
+                    else {
+                        continue;
+                    };
+                    let Some((callee, _)) = func.const_fn_def() else {
+                        continue;
+                    };
+                    for (i, _) in args.iter().enumerate().filter(|(_, arg)| holds(&arg.node)) {
+                        let reason = requirement(ctx, callee, i).filter(|reason| {
+                            // `&T` is `Send` if `T` is `Sync`
+                            *reason == Rejection::StaticArgument
+                                || !future::is_sync(ctx, def_id, ty)
+                        });
+                        if reason.is_some() {
+                            log::debug!("{local:?} is passed to {}", ctx.def_path_str(callee));
+                            return reason;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// What the `i`th parameter of `callee` is required to be by the bounds on
+/// its type: `'static` or else `Send`.
+fn requirement(ctx: &TyCtxt<'_>, callee: DefId, i: usize) -> Option<Rejection> {
+    let sig = ctx.fn_sig(callee).instantiate_identity().skip_binder();
+    let param = *sig.inputs().get(i)?;
+    if !matches!(param.kind(), TyKind::Param(_)) {
+        return None;
+    }
+    let mut requirement = None;
+    for clause in ctx
+        .predicates_of(callee)
+        .instantiate_identity(*ctx)
+        .predicates
+    {
+        match clause.kind().skip_binder() {
+            ClauseKind::TypeOutlives(outlives) if outlives.0 == param && outlives.1.is_static() => {
+                return Some(Rejection::StaticArgument);
+            }
+            ClauseKind::Trait(trait_pred)
+                if trait_pred.self_ty() == param
+                    && ctx.is_diagnostic_item(sym::Send, trait_pred.def_id()) =>
+            {
+                requirement = Some(Rejection::SendArgument);
+            }
+            _ => {}
+        }
+    }
+    requirement
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture;
+    use crate::rewrite::Rejection;
+    use crate::Options;
+
+    const PATH: &str = "sink_fixture.rs";
+
+    #[test]
+    fn spawned() {
+        let source = "fn f(s: String) -> usize {\n    let t = s.clone();\n    \
+                      std::thread::spawn(move || t.len());\n    s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "f", &Options::default());
+        assert!(rewrite.eliminations.is_empty());
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(reasons, [&Rejection::StaticArgument]);
+    }
+
+    #[test]
+    fn sent() {
+        // a `&Cell` is not `Send`, a `&String` is
+        let source = "fn send<T: Send>(t: T) -> T {\n    t\n}\n\
+                      fn cell(c: std::cell::Cell<u8>) -> u8 {\n    let d = c.clone();\n    send(d).get() + c.get()\n}\n\
+                      fn string(s: String) -> usize {\n    let t = s.clone();\n    send(t).len() + s.len()\n}";
+        let rewrite = fixture::rewrite(PATH, source, "--all", &Options::default());
+        assert_eq!(rewrite.eliminations.len(), 1);
+        let reasons: Vec<&Rejection> = rewrite.rejected.iter().map(|rej| &rej.reason).collect();
+        assert_eq!(reasons, [&Rejection::SendArgument]);
+        assert!(fixture::rewritten(&rewrite).contains("let t = s;"));
+    }
+}
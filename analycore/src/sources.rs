@@ -0,0 +1,132 @@
+//! The source files of the crate as the compiler read them. The offsets of
+//! the spans in a compilation, and so of the edits found in it, are in its
+//! `SourceMap` where each file starts past the end of the previous one: a
+//! `mod` or `include!` makes them differ from the offsets in the files, as
+//! do the `\r` of `\r\n` line endings and the BOM the compiler removes.
+
+use crate::rewrite::Range;
+use rustc_hir::def_id::LOCAL_CRATE;
+use rustc_middle::ty::TyCtxt;
+use rustc_span::FileName;
+use std::path::PathBuf;
+
+/// A source file of the crate.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// contents of the file
+    pub source: String,
+    /// offset of the file in the `SourceMap`
+    start: u32,
+    /// the offsets in the contents read by the compiler from which the
+    /// contents of the file are longer by a number of bytes
+    normalized: Vec<(u32, u32)>,
+}
+impl SourceFile {
+    /// The offset in the file of `offset` in the contents read by the
+    /// compiler. The end of a line is before its `\r\n`.
+    fn original(&self, offset: u32) -> u32 {
+        match self.normalized.partition_point(|(pos, _)| *pos <= offset) {
+            0 => offset,
+            i => offset + self.normalized[i - 1].1,
+        }
+    }
+}
+
+/// The source files of the crate in the `SourceMap` of a compilation.
+#[derive(Clone, Debug, Default)]
+pub struct Sources(Vec<SourceFile>);
+impl Sources {
+    /// The files on disk of the crate compiled by `ctx`, in the order they
+    /// were read: the crate root first.
+    pub fn new(ctx: &TyCtxt<'_>) -> Self {
+        let files = ctx.sess.source_map().files();
+        let files = files.iter().filter_map(|file| {
+            let FileName::Real(name) = &file.name else {
+                return None;
+            };
+            let (Some(path), Some(source)) = (name.local_path(), &file.src) else {
+                return None;
+            };
+            let normalized: Vec<_> = file
+                .normalized_pos
+                .iter()
+                .map(|normalized| (normalized.pos.0, normalized.diff))
+                .collect();
+            (file.cnum == LOCAL_CRATE).then(|| SourceFile {
+                path: path.to_owned(),
+                source: original(source, &normalized),
+                start: file.start_pos.0,
+                normalized,
+            })
+        });
+        Self(files.collect())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &SourceFile> {
+        self.0.iter()
+    }
+    /// The file the `SourceMap` offset `pos` is in, and the offset in it.
+    pub fn locate(&self, pos: u32) -> Option<(&SourceFile, u32)> {
+        let i = self
+            .0
+            .partition_point(|file| file.start <= pos)
+            .checked_sub(1)?;
+        let file = &self.0[i];
+        let offset = file.original(pos - file.start);
+        (offset as usize <= file.source.len()).then_some((file, offset))
+    }
+    /// The file `range` of the `SourceMap` is in, and the range in it.
+    pub fn local(&self, range: Range) -> Option<(&SourceFile, Range)> {
+        let (file, lo) = self.locate(range.lo)?;
+        let hi = file.original(range.hi.checked_sub(file.start)?);
+        (hi as usize <= file.source.len()).then_some((file, Range::new(lo, hi)))
+    }
+}
+
+/// The contents of a file out of the `source` the compiler read, which
+/// lacks the bytes of `normalized`: a BOM at offset 0, else the `\r` of a
+/// line ending before the offset.
+fn original(source: &str, normalized: &[(u32, u32)]) -> String {
+    let mut original = String::with_capacity(source.len() + normalized.len());
+    let mut copied = 0;
+    for (pos, _) in normalized {
+        if *pos == 0 {
+            original.push('\u{feff}');
+            continue;
+        }
+        let newline = *pos as usize - 1;
+        original.push_str(&source[copied..newline]);
+        original.push('\r');
+        copied = newline;
+    }
+    original.push_str(&source[copied..]);
+    original
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enter::{self, get_fn};
+    use crate::CompileResult;
+    use std::collections::HashMap;
+
+    #[test]
+    fn crlf_and_bom() {
+        let source = "\u{feff}// f\r\nfn f() {}\r\n\r\nfn main() {\r\n    f();\r\n}\r\n";
+        let path = PathBuf::from("sources_fixture.rs");
+        let sources = HashMap::from([(path.clone(), source.to_owned())]);
+        let res = enter::enter(path, sources, |ctx| {
+            let sources = Sources::new(ctx);
+            let span = |name| Range::from(ctx.def_span(get_fn(ctx, name).unwrap()));
+            let (file, f) = sources.local(span("f")).unwrap();
+            let (_, main) = sources.local(span("main")).unwrap();
+            Ok((file.source.clone(), f, main))
+        });
+        let Ok(CompileResult::Ok(Ok((contents, f, main)))) = res else {
+            panic!("{res:?}");
+        };
+        assert_eq!(contents, source);
+        assert_eq!(&source[f.lo as usize..f.hi as usize], "fn f()");
+        assert_eq!(&source[main.lo as usize..main.hi as usize], "fn main()");
+    }
+}
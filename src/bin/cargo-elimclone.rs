@@ -0,0 +1,186 @@
+#![feature(rustc_private)]
+
+//! `cargo elimclone <fn_name|--all> [--diff|--json|--in-place|--report|--report-json] [--fields] [--lifetimes=<letters|name>] [cargo check options]`
+//!
+//! Runs `cargo check` with this binary as `RUSTC_WORKSPACE_WRAPPER`, so every
+//! workspace member is rewritten with the exact arguments cargo computes for
+//! it (extern crates, cfgs, edition, target dir). `--workspace` is passed
+//! unless the packages are selected with `-p`, `--package` or `--workspace`.
+
+use analycore::rustc_driver::{self, Callbacks, RunCompiler};
+use analycore::rustc_interface::interface;
+use analycore::rustc_span::Symbol;
+use analycore::{rewrite_crate, rewrite_fn, Error, Input, Options, Output};
+use log::LevelFilter;
+use simple_logger::SimpleLogger;
+use std::env::temp_dir;
+use std::env::{args, current_exe, var};
+use std::fs::{self, read_to_string};
+use std::io::stdout;
+use std::process::{exit, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// elimclone arguments forwarded from the cargo subcommand to the wrapper
+const ARGS_ENV: &str = "ELIMCLONE_ARGS";
+/// changes on every run so that cargo never reuses a previous result
+const RUN_ENV: &str = "ELIMCLONE_RUN";
+/// file created by the wrapper when a member defines the function to rewrite
+const FOUND_ENV: &str = "ELIMCLONE_FOUND";
+
+fn main() {
+    // on stderr, so that the output can be piped; `RUST_LOG` overrides the level
+    SimpleLogger::new()
+        .with_level(LevelFilter::Warn)
+        .env()
+        .init()
+        .unwrap();
+
+    let args: Vec<String> = args().collect();
+    if let Ok(tool_args) = var(ARGS_ENV) {
+        exit(driver(&args[1..], &tool_args));
+    } else {
+        exit(cargo(&args[1..]));
+    }
+}
+
+/// Entry point as `cargo elimclone`.
+fn cargo(args: &[String]) -> i32 {
+    let args = match args.first().map(String::as_str) {
+        Some("elimclone") => &args[1..],
+        _ => args,
+    };
+    let Some((fn_name, mut check_args)) = args.split_first() else {
+        eprintln!(
+            "usage: cargo elimclone <fn_name|--all> [--diff|--json|--in-place|--report|--report-json] [--fields] [--lifetimes=<letters|name>] [cargo check options]"
+        );
+        return 1;
+    };
+    let mut tool_args = fn_name.clone();
+    while let Some((flag, rest)) = check_args.split_first() {
+        if Output::from_flag(flag).is_none() && !Options::default().set_flag(flag) {
+            break;
+        }
+        tool_args = format!("{tool_args}\n{flag}");
+        check_args = rest;
+    }
+    let run = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let selects_packages = check_args.iter().any(|arg| {
+        arg.starts_with("-p")
+            || arg.starts_with("--package")
+            || arg == "--workspace"
+            || arg == "--all"
+    });
+    let found = temp_dir().join(format!("elimclone-found-{run}"));
+    let status = Command::new(var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .arg("check")
+        .args((!selects_packages).then_some("--workspace"))
+        .args(check_args)
+        .env("RUSTC_WORKSPACE_WRAPPER", current_exe().unwrap())
+        .env(ARGS_ENV, tool_args)
+        .env(RUN_ENV, run.to_string())
+        .env(FOUND_ENV, &found)
+        .status();
+    let code = match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            log::error!("failed to run cargo: {e}");
+            return 1;
+        }
+    };
+    // each member is rewritten by its own wrapper process
+    let any_found = fs::remove_file(&found).is_ok();
+    if code == 0 && fn_name != "--all" && !any_found {
+        log::error!("{fn_name} not found in the selected packages");
+        return 1;
+    }
+    code
+}
+
+/// Entry point as `RUSTC_WORKSPACE_WRAPPER`; `args` starts with the path to rustc.
+fn driver(args: &[String], tool_args: &str) -> i32 {
+    let mut tool_args = tool_args.split('\n');
+    let fn_name = tool_args.next().unwrap_or_default();
+    let mut output = Output::Source;
+    let mut options = Options::default();
+    for flag in tool_args {
+        match Output::from_flag(flag) {
+            Some(flag) => output = flag,
+            None => {
+                options.set_flag(flag);
+            }
+        }
+    }
+    let input = Input::Args(args.to_vec());
+    if let Some(root) = input.root() {
+        if var("CARGO_PRIMARY_PACKAGE").is_ok() {
+            rewrite_crate_root(&input, &root.to_string_lossy(), fn_name, output, &options);
+        }
+    }
+    // build as usual so that the members depending on this crate can be checked
+    rustc_driver::catch_with_exit_code(|| RunCompiler::new(args, &mut TrackRun).run())
+}
+
+fn rewrite_crate_root(input: &Input, root: &str, fn_name: &str, output: Output, options: &Options) {
+    let Ok(source) = read_to_string(root) else {
+        log::error!("unable to read {root}");
+        return;
+    };
+    let res = if fn_name == "--all" {
+        rewrite_crate(input, source, options)
+    } else {
+        rewrite_fn(input, source, fn_name, options)
+    };
+    match res {
+        Ok(res) => {
+            if res.eliminations.is_empty() {
+                log::info!("unable to rewrite: {root}");
+            } else {
+                log::info!("rewrite success: {root}");
+            }
+            if !res.eliminations.is_empty() || output.is_report() {
+                if let Err(e) = res.write(output, &mut stdout().lock()) {
+                    log::error!("{root}: {e}");
+                }
+            }
+        }
+        // expected of the members that do not define the function, reported
+        // by `cargo` if none does
+        Err(Error::FnNotFound) => {
+            log::info!("{root}: {fn_name} not found");
+            return;
+        }
+        Err(Error::AmbiguousFn(paths)) => {
+            log::error!("{root}: {fn_name} is ambiguous: {}", paths.join(", "));
+        }
+        Err(Error::Compile(diagnostics)) => {
+            log::error!("{root}: compilation failed:\n{}", diagnostics.join("\n"));
+        }
+        Err(e) => {
+            log::error!("{root}: {e:?}");
+        }
+    }
+    if let Ok(found) = var(FOUND_ENV) {
+        if let Err(e) = fs::write(&found, "") {
+            log::error!("{found}: {e}");
+        }
+    }
+}
+
+/// Records the elimclone environment in the dep-info so that cargo reruns
+/// the wrapper whenever it changes.
+struct TrackRun;
+impl Callbacks for TrackRun {
+    fn config(&mut self, config: &mut interface::Config) {
+        config.psess_created = Some(Box::new(|psess| {
+            for name in [ARGS_ENV, RUN_ENV] {
+                psess.env_depinfo.get_mut().insert((
+                    Symbol::intern(name),
+                    var(name).ok().as_deref().map(Symbol::intern),
+                ));
+            }
+        }));
+    }
+}
@@ -1,22 +1,59 @@
 #![feature(rustc_private)]
 
-use analycore::rewrite_fn;
+use analycore::{rewrite_crate, rewrite_fn, Input, Options, Output};
+use log::LevelFilter;
+use simple_logger::SimpleLogger;
 use std::env::args;
 use std::fs::read_to_string;
+use std::io::stdout;
+use std::process::exit;
+
+const USAGE: &str = "usage: elimclone <file> <fn_name|--all> [--diff|--json|--in-place|--report|--report-json] [--fields] [--lifetimes=<letters|name>]";
 
 fn main() {
-    simple_logger::init_with_env().unwrap();
+    // on stderr, so that the output can be piped; `RUST_LOG` overrides the level
+    SimpleLogger::new()
+        .with_level(LevelFilter::Warn)
+        .env()
+        .init()
+        .unwrap();
 
-    let file = args().nth(1).unwrap();
-    let source = read_to_string(&file).unwrap();
-    let fn_name = args().nth(2).unwrap();
-    let res = rewrite_fn(file.into(), source, &fn_name);
+    let (Some(file), Some(fn_name)) = (args().nth(1), args().nth(2)) else {
+        eprintln!("{USAGE}");
+        exit(1);
+    };
+    let mut output = Output::Source;
+    let mut options = Options::default();
+    for flag in args().skip(3) {
+        match Output::from_flag(&flag) {
+            Some(flag) => output = flag,
+            None if options.set_flag(&flag) => {}
+            None => {
+                eprintln!("unknown flag {flag}\n{USAGE}");
+                exit(1);
+            }
+        }
+    }
+    let Ok(source) = read_to_string(&file) else {
+        log::error!("unable to read {file}");
+        exit(1);
+    };
+    let input = Input::File(file.into());
+    let res = if fn_name == "--all" {
+        rewrite_crate(&input, source, &options)
+    } else {
+        rewrite_fn(&input, source, &fn_name, &options)
+    };
     if let Ok(res) = res {
-        if let Some(res) = res {
-            log::info!("rewrite success");
-            println!("{}", res);
-        } else {
+        if res.eliminations.is_empty() {
             log::info!("unable to rewrite");
+        } else {
+            log::info!("rewrite success");
+        }
+        if !res.eliminations.is_empty() || output.is_report() {
+            if let Err(e) = res.write(output, &mut stdout().lock()) {
+                log::error!("{e}");
+            }
         }
     } else {
         log::error!("{:?}", res);